@@ -0,0 +1,12 @@
+use std::error::Error;
+
+fn main() -> Result<(), Box<dyn Error>> {
+  // the vendored protoc, so building doesn't need one installed
+  let mut config = prost_build::Config::new();
+  config.protoc_executable(protoc_bin_vendored::protoc_bin_path()?);
+
+  tonic_build::configure()
+    .build_client(false)
+    .compile_protos_with_config(config, &["proto/auth/v1/admin.proto"], &["proto"])?;
+  Ok(())
+}
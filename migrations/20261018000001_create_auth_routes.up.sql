@@ -0,0 +1,8 @@
+-- the versions of the route protection table, the latest one (by created_at) is loaded
+CREATE TABLE IF NOT EXISTS auth_routes (
+  version VARCHAR(64) PRIMARY KEY,
+  routes JSONB NOT NULL DEFAULT '[]'::jsonb,
+  created_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_routes_created_at ON auth_routes (created_at DESC);
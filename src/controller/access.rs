@@ -0,0 +1,210 @@
+use std::{collections::HashMap, sync::Arc};
+
+use megacommerce_proto::{service::auth::v3::CheckRequest, CachedUserData, JwtClaims};
+use megacommerce_shared::models::{context::Context, errors::BoxedErr};
+
+use crate::{
+  models::routes::{RouteEntry, RouteRule},
+  utils::net::get_essential_http_headers,
+};
+
+use super::{
+  claims::ClaimsViolation,
+  policy::{evaluate_policies, evaluate_policy, PolicyAttributes},
+  Controller,
+};
+
+/// The outcome of an authorization rule
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Decision {
+  Allow,
+  Deny { rule: String, reason: String },
+}
+
+impl Decision {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Decision::Allow => "allow",
+      Decision::Deny { .. } => "deny",
+    }
+  }
+}
+
+/// The outcome of validating the token of a request
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum TokenState {
+  Missing,
+  Invalid,
+  /// the forwarded claims failed the local checks
+  Rejected(ClaimsViolation),
+  /// the token is used from another device than it's bound to, and the route denies it
+  DeviceMismatch,
+  /// the token starts a session over the concurrent sessions limit of its user,
+  /// and the new session is terminated
+  SessionLimitExceeded,
+  /// the session of the token went unused past the idle timeout of its user,
+  /// and is terminated
+  IdleTimeout,
+  /// a degraded token is allowed without validation, because hydra is unreachable,
+  /// a device mismatch one is used from another device, and the route flags it,
+  /// `last_active` is the previous use of the session, None for a new one
+  Valid {
+    scopes: Vec<String>,
+    degraded: bool,
+    device_mismatch: bool,
+    last_active: Option<i64>,
+  },
+}
+
+impl Controller {
+  /// Decides on the route rules (enforced and shadow), then on the applicable policies,
+  /// shadow decisions are only recorded, the returned decision is the enforced one,
+  /// a public route is authorized with a missing token, so only its request-only
+  /// policies apply
+  pub(super) async fn authorize(
+    &self,
+    ctx: &Arc<Context>,
+    req: &CheckRequest,
+    route: &RouteEntry,
+    params: &HashMap<String, String>,
+    claims: &JwtClaims,
+    state: &TokenState,
+  ) -> Result<Decision, BoxedErr> {
+    let h = get_essential_http_headers(
+      req,
+      self.cached_config.available_languages.clone(),
+      self.cached_config.default_language.clone(),
+    );
+
+    // the policies over the token or the user only apply to requests holding a valid
+    // token, the ones over the request alone apply to every request
+    let valid = matches!(state, TokenState::Valid { .. });
+    let policies: Vec<_> = self
+      .policies
+      .applicable(&h.path)
+      .into_iter()
+      .filter(|p| valid || p.is_request_only())
+      .collect();
+    let needs_roles = |rule: &RouteRule| rule.protected && !rule.roles.is_empty();
+    let needs_user = valid
+      && (needs_roles(&route.rule)
+        || route.shadow.as_ref().is_some_and(needs_roles)
+        || !policies.is_empty());
+
+    let user = if needs_user {
+      // a missing user has no roles or attributes, so a rule or a policy needing them denies
+      self.get_or_insert_auth_cached_user_data(ctx.clone(), &claims.sub).await?.map(|u| u.data)
+    } else {
+      None
+    };
+
+    let decision = Self::rule_decision(&route.rule, state, user.as_ref());
+    if let Some(shadow) = &route.shadow {
+      let would_be = Self::rule_decision(shadow, state, user.as_ref());
+      self.record_shadow(ctx, "route", &h.method, &h.path, &decision, &would_be);
+    }
+
+    if policies.is_empty() {
+      return Ok(decision);
+    }
+
+    let scopes: &[String] = match state {
+      TokenState::Valid { scopes, .. } => scopes,
+      _ => &[],
+    };
+    let attrs = PolicyAttributes {
+      path: &h.path,
+      method: &h.method,
+      ip: &ctx.ip_address,
+      headers: &h.headers,
+      params,
+      claims,
+      scopes,
+      user: user.as_ref(),
+    };
+
+    // a route deny stands, the shadow policies are still recorded against it
+    let decision = match decision {
+      Decision::Allow => evaluate_policies(&policies, &attrs),
+      deny => deny,
+    };
+    for policy in policies.iter().filter(|p| p.shadow) {
+      let would_be = evaluate_policy(policy, &attrs);
+      let rule = format!("policy:{}", policy.name);
+      self.record_shadow(ctx, &rule, &h.method, &h.path, &decision, &would_be);
+    }
+
+    Ok(decision)
+  }
+
+  /// Decides on a route rule: the token, then the scopes, then the roles
+  fn rule_decision(
+    rule: &RouteRule,
+    state: &TokenState,
+    user: Option<&CachedUserData>,
+  ) -> Decision {
+    if !rule.protected {
+      return Decision::Allow;
+    }
+
+    let deny = |name: &str, reason: String| Decision::Deny { rule: name.into(), reason };
+    let scopes = match state {
+      TokenState::Missing => return deny("token", "the token id is missing".into()),
+      TokenState::Invalid => return deny("token", "the token is revoked or inactive".into()),
+      TokenState::Rejected(violation) => return deny("token", violation.to_string()),
+      TokenState::DeviceMismatch => {
+        return deny("token", "the token is bound to another device".into());
+      }
+      TokenState::SessionLimitExceeded => {
+        return deny("token", "the user has too many concurrent sessions".into());
+      }
+      TokenState::IdleTimeout => {
+        return deny("token", "the session is idle for too long".into());
+      }
+      TokenState::Valid { scopes, .. } => scopes,
+    };
+
+    if !rule.scopes.iter().all(|s| scopes.contains(s)) {
+      return deny(
+        "scopes",
+        format!("the token scopes {:?} don't cover {:?}", scopes, rule.scopes),
+      );
+    }
+
+    if !rule.roles.is_empty() {
+      let satisfied = user.is_some_and(|u| Self::check_roles(&rule.roles, u));
+      if !satisfied {
+        let roles = user.map(|u| u.roles.as_str()).unwrap_or_default();
+        return deny("roles", format!("the user roles {} don't satisfy {:?}", roles, rule.roles));
+      }
+    }
+
+    Decision::Allow
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::models::routes::RolesRequirement;
+
+  use super::*;
+
+  fn valid() -> TokenState {
+    TokenState::Valid { scopes: vec![], degraded: false, device_mismatch: false, last_active: None }
+  }
+
+  #[test]
+  fn a_missing_user_is_denied_by_the_roles() {
+    let roles = RolesRequirement { any_of: vec!["admin".into()], ..Default::default() };
+    let rule = RouteRule { protected: true, roles, ..Default::default() };
+    let decision = Controller::rule_decision(&rule, &valid(), None);
+    assert!(matches!(decision, Decision::Deny { rule, .. } if rule == "roles"));
+
+    let admin = CachedUserData { roles: "admin".into(), ..Default::default() };
+    assert_eq!(Controller::rule_decision(&rule, &valid(), Some(&admin)), Decision::Allow);
+
+    // a rule without roles doesn't need the user
+    let rule = RouteRule { protected: true, ..Default::default() };
+    assert_eq!(Controller::rule_decision(&rule, &valid(), None), Decision::Allow);
+  }
+}
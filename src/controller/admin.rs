@@ -0,0 +1,214 @@
+use tonic::{Request, Response, Status};
+
+use crate::{
+  proto::auth_admin::{
+    auth_admin_service_server::AuthAdminService, GetTokenStatusRequest, GetTokenStatusResponse,
+    ListUserSessionsRequest, ListUserSessionsResponse, RevokeDeviceTokensRequest,
+    RevokeTokenRequest, RevokeTokensResponse, RevokeUserTokensRequest, Session,
+    SetUserNotBeforeRequest, SetUserNotBeforeResponse, TerminateSessionRequest,
+    TerminateSessionResponse, TokenStatus,
+  },
+  utils::net::extract_jwt_token_from_request,
+};
+
+use super::{hydra::HydraValidation, redis::RedisClient, Controller};
+
+impl Controller {
+  /// Introspects the caller bearer token, and checks it's granted the admin scopes,
+  /// returns the caller identity (the token subject, or the client id of a service),
+  /// no admin scopes configured denies every caller
+  async fn authorize_admin<T>(&self, request: &Request<T>) -> Result<String, Status> {
+    let required = &self.service_config.admin.scopes;
+    if required.is_empty() {
+      return Err(Status::permission_denied("no admin scopes are configured"));
+    }
+
+    let token = extract_jwt_token_from_request(request)
+      .ok_or_else(|| Status::unauthenticated("a bearer token is required"))?;
+
+    match self.hydra.validate_token(&token).await {
+      Ok(HydraValidation::Valid { sub, client_id, scopes, .. }) => {
+        if !required.iter().all(|s| scopes.contains(s)) {
+          return Err(Status::permission_denied("the token isn't granted the admin scopes"));
+        }
+        Ok(if sub.is_empty() { client_id } else { sub })
+      }
+      Ok(HydraValidation::Invalid(_)) => Err(Status::unauthenticated("the token is invalid")),
+      Err(err) => {
+        self.report_internal_error(err);
+        Err(Status::internal("failed to validate the token"))
+      }
+    }
+  }
+}
+
+#[tonic::async_trait]
+impl AuthAdminService for Controller {
+  async fn revoke_token(
+    &self,
+    request: Request<RevokeTokenRequest>,
+  ) -> Result<Response<RevokeTokensResponse>, Status> {
+    let caller = self.authorize_admin(&request).await?;
+    let jti = &request.get_ref().jti;
+    if jti.is_empty() {
+      return Err(Status::invalid_argument("jti is required"));
+    }
+
+    let revoked = self.redis.revoke_token(jti, None).await.map_err(|err| {
+      self.report_internal_error(err);
+      Status::internal("failed to revoke the token")
+    })?;
+
+    tracing::info!(caller = %caller, jti = %jti, revoked = %revoked, "token revoked by admin");
+    Ok(Response::new(RevokeTokensResponse { revoked: revoked as u32 }))
+  }
+
+  async fn revoke_user_tokens(
+    &self,
+    request: Request<RevokeUserTokensRequest>,
+  ) -> Result<Response<RevokeTokensResponse>, Status> {
+    let caller = self.authorize_admin(&request).await?;
+    let user_id = &request.get_ref().user_id;
+    if user_id.is_empty() {
+      return Err(Status::invalid_argument("user_id is required"));
+    }
+
+    let revoked = self.redis.revoke_user_tokens(user_id).await.map_err(|err| {
+      self.report_internal_error(err);
+      Status::internal("failed to revoke the user tokens")
+    })?;
+
+    tracing::info!(caller = %caller, user_id = %user_id, revoked, "user tokens revoked by admin");
+    Ok(Response::new(RevokeTokensResponse { revoked }))
+  }
+
+  async fn revoke_device_tokens(
+    &self,
+    request: Request<RevokeDeviceTokensRequest>,
+  ) -> Result<Response<RevokeTokensResponse>, Status> {
+    let caller = self.authorize_admin(&request).await?;
+    let device_id = &request.get_ref().device_id;
+    if device_id.is_empty() {
+      return Err(Status::invalid_argument("device_id is required"));
+    }
+
+    let revoked = self.redis.revoke_device_tokens(device_id).await.map_err(|err| {
+      self.report_internal_error(err);
+      Status::internal("failed to revoke the device tokens")
+    })?;
+
+    tracing::info!(
+      caller = %caller,
+      device_id = %device_id,
+      revoked,
+      "device tokens revoked by admin"
+    );
+    Ok(Response::new(RevokeTokensResponse { revoked }))
+  }
+
+  async fn set_user_not_before(
+    &self,
+    request: Request<SetUserNotBeforeRequest>,
+  ) -> Result<Response<SetUserNotBeforeResponse>, Status> {
+    let caller = self.authorize_admin(&request).await?;
+    let user_id = &request.get_ref().user_id;
+    if user_id.is_empty() {
+      return Err(Status::invalid_argument("user_id is required"));
+    }
+
+    let not_before = self.redis.set_user_not_before(user_id).await.map_err(|err| {
+      self.report_internal_error(err);
+      Status::internal("failed to set the user not-before epoch")
+    })?;
+
+    tracing::info!(
+      caller = %caller,
+      user_id = %user_id,
+      not_before,
+      "user tokens issued before the epoch revoked by admin"
+    );
+    Ok(Response::new(SetUserNotBeforeResponse { not_before }))
+  }
+
+  async fn get_token_status(
+    &self,
+    request: Request<GetTokenStatusRequest>,
+  ) -> Result<Response<GetTokenStatusResponse>, Status> {
+    self.authorize_admin(&request).await?;
+    let jti = &request.get_ref().jti;
+    if jti.is_empty() {
+      return Err(Status::invalid_argument("jti is required"));
+    }
+
+    let path = "auth.controller.get_token_status";
+    let status = self.redis.get_token(jti, path).await.map_err(|err| {
+      self.report_internal_error(err);
+      Status::internal("failed to get the token status")
+    })?;
+
+    let res = match status {
+      Some(st) => GetTokenStatusResponse {
+        status: Some(TokenStatus {
+          dev_id: st.status.dev_id,
+          last_checked: st.status.last_checked,
+          revoked: st.status.revoked,
+        }),
+        scopes: st.scopes.unwrap_or_default(),
+        exp: st.exp.unwrap_or_default(),
+      },
+      None => GetTokenStatusResponse::default(),
+    };
+    Ok(Response::new(res))
+  }
+
+  async fn list_user_sessions(
+    &self,
+    request: Request<ListUserSessionsRequest>,
+  ) -> Result<Response<ListUserSessionsResponse>, Status> {
+    self.authorize_admin(&request).await?;
+    let user_id = &request.get_ref().user_id;
+    if user_id.is_empty() {
+      return Err(Status::invalid_argument("user_id is required"));
+    }
+
+    let sessions = self.redis.list_user_sessions(user_id).await.map_err(|err| {
+      self.report_internal_error(err);
+      Status::internal("failed to list the user sessions")
+    })?;
+
+    let sessions = sessions
+      .into_iter()
+      .map(|s| Session {
+        jti: s.jti,
+        device_id: s.device_id,
+        first_seen: s.first_seen,
+        last_seen: s.last_seen,
+        ip: s.ip,
+        user_agent: s.user_agent,
+        exp: s.exp.unwrap_or_default(),
+      })
+      .collect();
+    Ok(Response::new(ListUserSessionsResponse { sessions }))
+  }
+
+  async fn terminate_session(
+    &self,
+    request: Request<TerminateSessionRequest>,
+  ) -> Result<Response<TerminateSessionResponse>, Status> {
+    let caller = self.authorize_admin(&request).await?;
+    let TerminateSessionRequest { user_id, jti } = request.get_ref();
+    if user_id.is_empty() || jti.is_empty() {
+      return Err(Status::invalid_argument("user_id and jti are required"));
+    }
+
+    let terminated = self.redis.terminate_session(user_id, jti).await.map_err(|err| {
+      self.report_internal_error(err);
+      Status::internal("failed to terminate the session")
+    })?;
+
+    if terminated {
+      tracing::info!(caller = %caller, user_id = %user_id, jti = %jti, "session terminated by admin");
+    }
+    Ok(Response::new(TerminateSessionResponse { terminated }))
+  }
+}
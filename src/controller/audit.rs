@@ -1,7 +1,7 @@
-use megacommerce_shared::models::errors::BoxedErr;
+use megacommerce_shared::models::{context::Context, errors::BoxedErr};
 use tokio::spawn;
 
-use super::Controller;
+use super::{access::Decision, Controller};
 
 impl Controller {
   pub fn report_internal_error(&self, err: BoxedErr) {
@@ -10,4 +10,40 @@ impl Controller {
       let con = redis.get().await.get().await;
     });
   }
+
+  /// Records the decision a shadow rule would have made next to the enforced decision
+  pub(super) fn record_shadow(
+    &self,
+    ctx: &Context,
+    rule: &str,
+    method: &str,
+    path: &str,
+    enforced: &Decision,
+    shadow: &Decision,
+  ) {
+    let labels = [("rule", rule), ("enforced", enforced.as_str()), ("shadow", shadow.as_str())];
+    self.metrics.incr("auth_shadow_decisions_total", &labels);
+
+    if let Decision::Deny { rule: denied_by, reason } = shadow {
+      tracing::info!(
+        rule = %rule,
+        denied_by = %denied_by,
+        reason = %reason,
+        enforced = %enforced.as_str(),
+        method = %method,
+        path = %path,
+        request_id = %ctx.request_id,
+        "shadow rule would deny the request"
+      );
+    } else if enforced != shadow {
+      tracing::info!(
+        rule = %rule,
+        enforced = %enforced.as_str(),
+        method = %method,
+        path = %path,
+        request_id = %ctx.request_id,
+        "shadow rule would allow the request"
+      );
+    }
+  }
 }
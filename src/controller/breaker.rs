@@ -0,0 +1,282 @@
+use std::{
+  io::Error,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use derive_more::Display;
+use megacommerce_shared::models::errors::{BoxedErr, ErrorType, InternalError};
+use tokio::time::{sleep, timeout};
+use tonic::async_trait;
+
+use crate::models::config::HydraConfig;
+
+use super::{
+  hydra::{HydraClient, HydraValidation},
+  metrics::Metrics,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Display)]
+pub enum CircuitState {
+  #[display("closed")]
+  Closed,
+  #[display("open")]
+  Open,
+  /// open, with a single probe request in flight
+  #[display("half_open")]
+  HalfOpen,
+}
+
+/// Opens after consecutive failures, while open the calls fail fast, and once
+/// `open_secs` pass a single probe call is let through, to close it again or reopen it
+#[derive(Debug)]
+pub struct CircuitBreaker {
+  failure_threshold: u32,
+  open_for: Duration,
+  metrics: Arc<Metrics>,
+  inner: Mutex<BreakerInner>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerInner {
+  failures: u32,
+  opened_at: Option<Instant>,
+  probing: bool,
+}
+
+impl CircuitBreaker {
+  pub fn new(failure_threshold: u32, open_secs: u64, metrics: Arc<Metrics>) -> Self {
+    metrics.set_gauge("auth_hydra_circuit_open", &[], 0);
+    Self {
+      failure_threshold: failure_threshold.max(1),
+      open_for: Duration::from_secs(open_secs),
+      metrics,
+      inner: Mutex::new(BreakerInner::default()),
+    }
+  }
+
+  pub fn state(&self) -> CircuitState {
+    let inner = self.inner.lock().unwrap();
+    match (inner.opened_at, inner.probing) {
+      (None, _) => CircuitState::Closed,
+      (Some(_), true) => CircuitState::HalfOpen,
+      (Some(_), false) => CircuitState::Open,
+    }
+  }
+
+  /// Checks if a call can be made, an expired open circuit lets one probe call through,
+  /// the probe is released by the outcome recorded on the permit, or by dropping it
+  fn allow(&self) -> Option<Permit<'_>> {
+    let mut inner = self.inner.lock().unwrap();
+    match inner.opened_at {
+      None => Some(Permit { breaker: self, probe: false }),
+      Some(at) if !inner.probing && at.elapsed() >= self.open_for => {
+        inner.probing = true;
+        Some(Permit { breaker: self, probe: true })
+      }
+      Some(_) => None,
+    }
+  }
+
+  fn record_success(&self) {
+    let mut inner = self.inner.lock().unwrap();
+    if inner.opened_at.is_some() {
+      tracing::info!("the hydra circuit is closed");
+      self.metrics.set_gauge("auth_hydra_circuit_open", &[], 0);
+    }
+    *inner = BreakerInner::default();
+  }
+
+  /// Reopens the circuit of a probe that was dropped without an outcome, E,g its request
+  /// was cancelled, so a later call can probe again once `open_secs` pass
+  fn release_probe(&self) {
+    let mut inner = self.inner.lock().unwrap();
+    if inner.probing {
+      inner.opened_at = Some(Instant::now());
+      inner.probing = false;
+    }
+  }
+
+  fn record_failure(&self) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.failures += 1;
+    if inner.probing || (inner.opened_at.is_none() && inner.failures >= self.failure_threshold) {
+      tracing::warn!(failures = inner.failures, "the hydra circuit is open");
+      self.metrics.set_gauge("auth_hydra_circuit_open", &[], 1);
+      inner.opened_at = Some(Instant::now());
+      inner.probing = false;
+    }
+  }
+}
+
+/// A call let through by the circuit breaker, its outcome must be recorded on it
+struct Permit<'a> {
+  breaker: &'a CircuitBreaker,
+  probe: bool,
+}
+
+impl Permit<'_> {
+  fn success(mut self) {
+    self.probe = false;
+    self.breaker.record_success();
+  }
+
+  fn failure(mut self) {
+    self.probe = false;
+    self.breaker.record_failure();
+  }
+}
+
+impl Drop for Permit<'_> {
+  fn drop(&mut self) {
+    if self.probe {
+      self.breaker.release_probe();
+    }
+  }
+}
+
+/// The longest delay between two retries, before the jitter
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Wraps a hydra client with bounded retries (exponential backoff with jitter),
+/// within a total budget, and a circuit breaker, introspection is idempotent, so it's
+/// safe to retry
+#[derive(Debug)]
+pub struct ResilientHydraClient<C: HydraClient> {
+  pub inner: C,
+  pub breaker: Arc<CircuitBreaker>,
+  pub retries: u32,
+  pub retry_backoff: Duration,
+  pub retry_budget: Duration,
+  pub metrics: Arc<Metrics>,
+}
+
+impl<C: HydraClient> ResilientHydraClient<C> {
+  pub fn new(inner: C, config: &HydraConfig, breaker: Arc<CircuitBreaker>) -> Self {
+    let metrics = breaker.metrics.clone();
+    let retry_backoff = Duration::from_millis(config.retry_backoff_ms);
+    let retry_budget = Duration::from_millis(config.retry_budget_ms);
+    Self { inner, breaker, retries: config.retries, retry_backoff, retry_budget, metrics }
+  }
+
+  /// The delay before the retry after `attempt`, it doubles per attempt up to `MAX_BACKOFF`,
+  /// plus up to as much jitter
+  fn backoff(&self, attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.min(16));
+    let base = self.retry_backoff.saturating_mul(factor).min(MAX_BACKOFF);
+    base + base.mul_f64(rand::random::<f64>())
+  }
+}
+
+#[async_trait]
+impl<C: HydraClient> HydraClient for ResilientHydraClient<C> {
+  async fn validate_token(&self, token: &str) -> Result<HydraValidation, BoxedErr> {
+    let path = "auth.controller.validate_token";
+    let ie = |msg: &str| -> BoxedErr {
+      let err = Box::new(Error::other(msg.to_string()));
+      Box::new(InternalError::new(path.into(), err, ErrorType::Internal, true, msg.into()))
+    };
+
+    let Some(permit) = self.breaker.allow() else {
+      self.metrics.incr("auth_hydra_requests_total", &[("result", "rejected")]);
+      return Err(ie("the hydra circuit is open"));
+    };
+
+    let deadline = Instant::now() + self.retry_budget;
+    let mut attempt = 0;
+    loop {
+      let remaining = deadline.saturating_duration_since(Instant::now());
+      let res = match timeout(remaining, self.inner.validate_token(token)).await {
+        Ok(res) => res,
+        Err(_) => Err(ie("the hydra introspection ran out of its retry budget")),
+      };
+
+      let backoff = self.backoff(attempt);
+      match res {
+        Ok(res) => {
+          self.metrics.incr("auth_hydra_requests_total", &[("result", "ok")]);
+          permit.success();
+          return Ok(res);
+        }
+        Err(err) if attempt < self.retries && Instant::now() + backoff < deadline => {
+          tracing::debug!(err = %err, attempt, "hydra introspection failed, retrying");
+          self.metrics.incr("auth_hydra_requests_total", &[("result", "retry")]);
+          sleep(backoff).await;
+          attempt += 1;
+        }
+        Err(err) => {
+          self.metrics.incr("auth_hydra_requests_total", &[("result", "error")]);
+          permit.failure();
+          return Err(err);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{AtomicU32, Ordering};
+
+  use super::*;
+
+  /// Fails every call after `delay`, counting the calls
+  #[derive(Debug, Default)]
+  struct FailingHydra {
+    delay: Duration,
+    calls: AtomicU32,
+  }
+
+  #[async_trait]
+  impl HydraClient for FailingHydra {
+    async fn validate_token(&self, _token: &str) -> Result<HydraValidation, BoxedErr> {
+      self.calls.fetch_add(1, Ordering::SeqCst);
+      sleep(self.delay).await;
+      Err(Box::new(Error::other("connection refused")))
+    }
+  }
+
+  fn client(delay: Duration, retries: u32, budget_ms: u64) -> ResilientHydraClient<FailingHydra> {
+    let config = HydraConfig {
+      retries,
+      retry_backoff_ms: 10,
+      retry_budget_ms: budget_ms,
+      ..Default::default()
+    };
+    let breaker = Arc::new(CircuitBreaker::new(1, 0, Arc::new(Metrics::default())));
+    ResilientHydraClient::new(FailingHydra { delay, ..Default::default() }, &config, breaker)
+  }
+
+  #[tokio::test]
+  async fn a_dropped_probe_releases_the_circuit() {
+    let client = client(Duration::from_secs(10), 0, 60_000);
+    client.breaker.record_failure();
+    assert_eq!(client.breaker.state(), CircuitState::Open);
+
+    let probe = client.validate_token("token");
+    let res = timeout(Duration::from_millis(50), probe).await;
+    assert!(res.is_err());
+    assert_eq!(client.breaker.state(), CircuitState::Open);
+    assert!(client.breaker.allow().is_some());
+  }
+
+  #[tokio::test]
+  async fn the_retries_stop_at_the_budget() {
+    let client = client(Duration::from_millis(100), 10, 250);
+    let started = Instant::now();
+    assert!(client.validate_token("token").await.is_err());
+    assert!(started.elapsed() < Duration::from_millis(400));
+    assert!(client.inner.calls.load(Ordering::SeqCst) <= 3);
+  }
+
+  #[test]
+  fn the_backoff_is_capped() {
+    let client = client(Duration::ZERO, u32::MAX, 60_000);
+    for attempt in [0, 1, 10, 31, 32, 64, u32::MAX] {
+      let backoff = client.backoff(attempt);
+      assert!(backoff >= Duration::from_millis(10), "{}", attempt);
+      assert!(backoff <= MAX_BACKOFF * 2, "{}", attempt);
+    }
+    assert!(client.backoff(1) < Duration::from_millis(40));
+  }
+}
@@ -0,0 +1,114 @@
+use derive_more::Display;
+use megacommerce_proto::{service::auth::v3::CheckRequest, JwtClaims, Timestamp};
+
+use crate::{
+  models::config::{ClaimsConfig, ClaimsSource},
+  utils::claims::claims_from_jwt_authn_metadata,
+};
+
+use super::{jwks::JwksHydraClient, Controller};
+
+/// Why the forwarded claims of a token are rejected
+#[derive(Debug, Clone, Copy, PartialEq, Display)]
+pub(super) enum ClaimsViolation {
+  #[display("the token is expired")]
+  Expired,
+  #[display("the token isn't valid yet")]
+  NotYetValid,
+  #[display("the token issuer isn't trusted")]
+  UntrustedIssuer,
+  #[display("the token audience isn't accepted")]
+  AudienceMismatch,
+  /// the `sub` or `jti` of the validated token differ from the forwarded claims
+  #[display("the claims don't belong to the token")]
+  TokenMismatch,
+}
+
+impl ClaimsViolation {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ClaimsViolation::Expired => "expired",
+      ClaimsViolation::NotYetValid => "not_yet_valid",
+      ClaimsViolation::UntrustedIssuer => "untrusted_issuer",
+      ClaimsViolation::AudienceMismatch => "audience_mismatch",
+      ClaimsViolation::TokenMismatch => "token_mismatch",
+    }
+  }
+}
+
+/// Checks the `exp` and `nbf` of the claims, within the clock skew, then the issuer and
+/// audience allowlists, a missing `exp` or `nbf` isn't checked, hydra checks the token
+pub(super) fn check_claims(
+  config: &ClaimsConfig,
+  claims: &JwtClaims,
+  now: i64,
+) -> Result<(), ClaimsViolation> {
+  let skew = config.clock_skew_secs as i64;
+  let seconds = |t: &Option<Timestamp>| t.as_ref().map(|t| t.seconds).filter(|secs| *secs > 0);
+
+  if seconds(&claims.exp).is_some_and(|exp| now >= exp + skew) {
+    return Err(ClaimsViolation::Expired);
+  }
+  if seconds(&claims.nbf).is_some_and(|nbf| now < nbf - skew) {
+    return Err(ClaimsViolation::NotYetValid);
+  }
+  if !config.issuers.is_empty() && !config.issuers.contains(&claims.iss) {
+    return Err(ClaimsViolation::UntrustedIssuer);
+  }
+  if !config.audiences.is_empty() && !claims.aud.iter().any(|a| config.audiences.contains(a)) {
+    return Err(ClaimsViolation::AudienceMismatch);
+  }
+
+  Ok(())
+}
+
+/// Checks that a validated token is the one the claims were read from, the `sub` and `jti`
+/// hydra or the local verification report must match the claims, an unknown one is skipped
+pub(super) fn check_token_match(
+  claims: &JwtClaims,
+  sub: &str,
+  jti: &str,
+) -> Result<(), ClaimsViolation> {
+  if (!sub.is_empty() && sub != claims.sub) || (!jti.is_empty() && jti != claims.jti) {
+    return Err(ClaimsViolation::TokenMismatch);
+  }
+
+  Ok(())
+}
+
+impl Controller {
+  /// Reads the JWT claims of a check request from the configured sources in priority order,
+  /// the first source holding a token id or a subject is used, a source that can't verify
+  /// the token (E,g the jwks can't be fetched) is skipped
+  pub(super) async fn request_claims(
+    &self,
+    req: &CheckRequest,
+    raw_token: Option<&str>,
+  ) -> JwtClaims {
+    let config = &self.service_config.claims;
+    for source in &config.sources {
+      let claims = match source {
+        ClaimsSource::JwtAuthnMetadata => claims_from_jwt_authn_metadata(req, config),
+        ClaimsSource::VerifiedToken => self.verified_token_claims(raw_token).await,
+      };
+
+      if let Some(claims) = claims.filter(|c| !c.jti.is_empty() || !c.sub.is_empty()) {
+        return claims;
+      }
+    }
+
+    JwtClaims::default()
+  }
+
+  async fn verified_token_claims(&self, raw_token: Option<&str>) -> Option<JwtClaims> {
+    let jwks = self.jwks.as_ref()?;
+    let token = raw_token.filter(|t| JwksHydraClient::is_jwt(t))?;
+    match jwks.verified_claims(token).await {
+      Ok(claims) => claims,
+      Err(err) => {
+        tracing::warn!(err = %err, "failed to verify the token claims locally");
+        None
+      }
+    }
+  }
+}
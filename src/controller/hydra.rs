@@ -1,4 +1,7 @@
-use std::io::{Error, ErrorKind};
+use std::{
+  fmt,
+  io::{Error, ErrorKind},
+};
 
 use derive_more::Display;
 use megacommerce_shared::models::errors::{BoxedErr, ErrorType, InternalError};
@@ -7,15 +10,23 @@ use serde::Deserialize;
 use tonic::async_trait;
 
 /// Represents the result of a Hydra token validation.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum HydraValidation {
-  Valid { sub: String, exp: i64 },
+  /// `jti` is empty when it's unknown, hydra doesn't report it on introspection
+  Valid {
+    sub: String,
+    jti: String,
+    exp: i64,
+    scopes: Vec<String>,
+    client_id: String,
+    aud: Vec<String>,
+  },
   Invalid(String), // reason why token is invalid
 }
 
 /// Trait for Hydra client behavior
 #[async_trait]
-pub trait HydraClient: Send + Sync {
+pub trait HydraClient: fmt::Debug + Send + Sync {
   async fn validate_token(&self, token: &str) -> Result<HydraValidation, BoxedErr>;
 }
 
@@ -30,14 +41,22 @@ pub struct DefaultHydraClient {
 
 #[derive(Debug, Deserialize, Display)]
 #[display(
-    "IntrospectionResponse: active: {active}, sub: {sub}, exp: {exp}",
+    "IntrospectionResponse: active: {active}, sub: {sub}, exp: {exp}, client_id: {client_id}, token_type: {token_type}",
     sub = sub.as_deref().unwrap_or("None"),
-    exp = exp.map(|e| e.to_string()).as_deref().unwrap_or("None"))
+    exp = exp.map(|e| e.to_string()).as_deref().unwrap_or("None"),
+    client_id = client_id.as_deref().unwrap_or("None"),
+    token_type = token_type.as_deref().unwrap_or("None"))
 ]
 struct IntrospectionResponse {
   active: bool,
   sub: Option<String>,
   exp: Option<i64>,
+  /// space delimited list of the granted scopes
+  scope: Option<String>,
+  client_id: Option<String>,
+  #[serde(default)]
+  aud: Vec<String>,
+  token_type: Option<String>,
 }
 
 #[async_trait]
@@ -71,7 +90,11 @@ impl HydraClient for DefaultHydraClient {
     if body.active {
       return Ok(HydraValidation::Valid {
         sub: body.sub.unwrap_or_default(),
+        jti: String::new(),
         exp: body.exp.unwrap_or(0),
+        scopes: body.scope.unwrap_or_default().split_whitespace().map(String::from).collect(),
+        client_id: body.client_id.unwrap_or_default(),
+        aud: body.aud,
       });
     } else {
       Ok(HydraValidation::Invalid(format!("the token is invalid: {}", body).into()))
@@ -0,0 +1,270 @@
+use std::{
+  collections::HashMap,
+  io::Error,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use megacommerce_proto::JwtClaims;
+use megacommerce_shared::{
+  models::errors::{BoxedErr, ErrorType, InternalError},
+  utils::time::time_get_seconds,
+};
+use tokio::{
+  sync::OnceCell,
+  time::{sleep, Instant},
+};
+
+use crate::utils::token::token_hash;
+
+use super::{
+  claims::check_token_match,
+  hydra::{HydraClient, HydraValidation},
+  metrics::Metrics,
+  redis::{DefaultRedisClient, RedisCheck, RedisClient},
+};
+
+/// An introspection shared by the concurrent callers of a token, the error is kept as
+/// a message, only the caller that ran the introspection gets the error itself
+type Flight = Arc<OnceCell<Result<HydraValidation, String>>>;
+
+/// Introspects tokens against hydra, and records the result in their cached status,
+/// the concurrent introspections of a token are coalesced into a single one, within
+/// the instance, and across the instances with `TokensConfig::introspection_lock_ms`,
+/// the coalesced callers share a result only if they hold the same token, not the same jti
+#[derive(Debug)]
+pub(super) struct Introspector {
+  hydra: Arc<dyn HydraClient>,
+  redis: DefaultRedisClient,
+  metrics: Arc<Metrics>,
+  // token hash => the introspection in flight
+  inflight: Mutex<HashMap<String, Flight>>,
+}
+
+impl Introspector {
+  pub fn new(
+    hydra: Arc<dyn HydraClient>,
+    redis: DefaultRedisClient,
+    metrics: Arc<Metrics>,
+  ) -> Self {
+    Self { hydra, redis, metrics, inflight: Mutex::new(HashMap::new()) }
+  }
+
+  /// Introspects the token, or waits for the introspection of the same token in flight,
+  /// a valid token is bound to `device_id` unless it's already bound, and its session is
+  /// recorded, a None device starts no session (E,g a shadow rule or a refresh), the callers
+  /// waiting for a flight share the device of its leader
+  pub async fn introspect(
+    &self,
+    claims: &JwtClaims,
+    token: &str,
+    device_id: Option<&str>,
+  ) -> Result<HydraValidation, BoxedErr> {
+    let hash = token_hash(token);
+    let flight = {
+      let mut inflight = self.inflight.lock().unwrap();
+      match inflight.get(&hash) {
+        Some(flight) => {
+          self.metrics.incr("auth_introspections_coalesced_total", &[("scope", "instance")]);
+          flight.clone()
+        }
+        None => {
+          let flight = Flight::default();
+          inflight.insert(hash.clone(), flight.clone());
+          flight
+        }
+      }
+    };
+
+    // if the caller running the introspection is dropped, a waiting one takes over
+    let mut failure = None;
+    let res = flight
+      .get_or_init(|| async {
+        let res = self.introspect_locked(claims, token, &hash, device_id).await;
+        self.land(&hash, &flight);
+        res.map_err(|err| {
+          let msg = err.to_string();
+          failure = Some(err);
+          msg
+        })
+      })
+      .await;
+
+    match res {
+      Ok(validation) => Ok(validation.clone()),
+      Err(msg) => Err(failure.unwrap_or_else(|| {
+        let path = "auth.controller.introspect".to_string();
+        let err = Box::new(Error::other(msg.clone()));
+        Box::new(InternalError::new(path, err, ErrorType::Internal, true, msg.clone()))
+      })),
+    }
+  }
+
+  /// Removes the landed flight, a later caller starts a fresh introspection
+  fn land(&self, hash: &str, flight: &Flight) {
+    let mut inflight = self.inflight.lock().unwrap();
+    if inflight.get(hash).is_some_and(|f| Arc::ptr_eq(f, flight)) {
+      inflight.remove(hash);
+    }
+  }
+
+  /// Takes the redis introspection lock of the token if enabled, if another instance
+  /// holds it, waits for the status it records instead, until the lock expires
+  async fn introspect_locked(
+    &self,
+    claims: &JwtClaims,
+    token: &str,
+    hash: &str,
+    device_id: Option<&str>,
+  ) -> Result<HydraValidation, BoxedErr> {
+    let lock_ms = self.redis.tokens.introspection_lock_ms;
+    if lock_ms == 0 {
+      return self.introspect_and_record(claims, token, hash, device_id).await;
+    }
+
+    let jti = claims.jti.as_str();
+    let started = time_get_seconds() as i64;
+    // the lock is an optimization, a redis failure shouldn't fail the introspection
+    let owner = match self.redis.lock_introspection(jti, lock_ms).await {
+      Ok(Some(owner)) => Some(owner),
+      Ok(None) => {
+        // introspected here anyway if its holder records nothing before the lock expires
+        let recorded = self.wait_for_status(jti, hash, started, lock_ms).await;
+        if let Some(validation) = recorded {
+          self.metrics.incr("auth_introspections_coalesced_total", &[("scope", "cluster")]);
+          return Ok(validation);
+        }
+        None
+      }
+      Err(_) => None,
+    };
+
+    let res = self.introspect_and_record(claims, token, hash, device_id).await;
+    if let Some(owner) = owner {
+      self.redis.unlock_introspection(jti, &owner).await.ok();
+    }
+    res
+  }
+
+  /// Polls the token status until it's checked after `started`, or `timeout_ms` pass,
+  /// a valid status is only taken if it was recorded for the token of `hash`
+  async fn wait_for_status(
+    &self,
+    jti: &str,
+    hash: &str,
+    started: i64,
+    timeout_ms: u64,
+  ) -> Option<HydraValidation> {
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    while Instant::now() < deadline {
+      sleep(Duration::from_millis(50)).await;
+      match self.redis.check_token(jti).await {
+        Ok(RedisCheck::Revoked(reason)) => return Some(HydraValidation::Invalid(reason)),
+        Ok(RedisCheck::Allowed { status: Some(st) })
+          if st.status.last_checked >= started && st.token_hash.as_deref() == Some(hash) =>
+        {
+          return Some(HydraValidation::Valid {
+            sub: String::new(),
+            jti: String::new(),
+            exp: st.exp.unwrap_or_default(),
+            scopes: st.scopes.unwrap_or_default(),
+            client_id: String::new(),
+            aud: vec![],
+          });
+        }
+        _ => {}
+      }
+    }
+
+    None
+  }
+
+  /// Introspects the token, and records the result in its cached status, a token revoked
+  /// here while it was introspected stays revoked, and is reported invalid, a valid token
+  /// that doesn't match its claims isn't recorded under their jti, the caller rejects it
+  async fn introspect_and_record(
+    &self,
+    claims: &JwtClaims,
+    token: &str,
+    hash: &str,
+    device_id: Option<&str>,
+  ) -> Result<HydraValidation, BoxedErr> {
+    let jti = claims.jti.as_str();
+    let claims_exp = claims.exp.as_ref().map(|t| t.seconds).filter(|exp| *exp > 0);
+    let validation = self.hydra.validate_token(token).await?;
+
+    // TODO: handle mark_checked_ok, revoke_token errors
+    match &validation {
+      HydraValidation::Valid { sub, jti: token_jti, .. }
+        if check_token_match(claims, sub, token_jti).is_err() => {}
+      HydraValidation::Valid { sub, scopes, exp, .. } => {
+        let exp = (*exp > 0).then_some(*exp).or(claims_exp);
+        let sub = if sub.is_empty() { &claims.sub } else { sub };
+        let recorded = self.redis.mark_checked_ok(jti, hash, sub, scopes, exp, device_id).await;
+        if let Ok(false) = recorded {
+          return Ok(HydraValidation::Invalid("the token was revoked".into()));
+        }
+      }
+      HydraValidation::Invalid(_) => {
+        self.redis.revoke_token(jti, claims_exp).await.ok();
+      }
+    }
+
+    Ok(validation)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{AtomicU32, Ordering};
+
+  use tokio::join;
+  use tonic::async_trait;
+
+  use crate::models::config::Config as ServiceConfig;
+
+  use super::{super::Controller, *};
+
+  /// Accepts only `valid-token`, after a delay, so concurrent introspections overlap
+  #[derive(Debug, Default)]
+  struct SlowHydra {
+    calls: AtomicU32,
+  }
+
+  #[async_trait]
+  impl HydraClient for SlowHydra {
+    async fn validate_token(&self, token: &str) -> Result<HydraValidation, BoxedErr> {
+      self.calls.fetch_add(1, Ordering::SeqCst);
+      sleep(Duration::from_millis(100)).await;
+      match token {
+        "valid-token" => Ok(HydraValidation::Valid {
+          sub: "user-1".into(),
+          jti: "jti-1".into(),
+          exp: 0,
+          scopes: vec![],
+          client_id: String::new(),
+          aud: vec![],
+        }),
+        _ => Ok(HydraValidation::Invalid("inactive".into())),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn tokens_sharing_a_jti_are_not_coalesced() {
+    let hydra = Arc::new(SlowHydra::default());
+    let controller = Controller::stub(ServiceConfig::default(), hydra.clone());
+    let claims = JwtClaims { jti: "jti-1".into(), ..Default::default() };
+
+    let introspector = &controller.introspector;
+    let (valid, forged, again) = join!(
+      introspector.introspect(&claims, "valid-token", Some("")),
+      introspector.introspect(&claims, "forged-token", Some("")),
+      introspector.introspect(&claims, "valid-token", Some("")),
+    );
+    assert!(matches!(valid.unwrap(), HydraValidation::Valid { .. }));
+    assert!(matches!(forged.unwrap(), HydraValidation::Invalid(_)));
+    assert!(matches!(again.unwrap(), HydraValidation::Valid { .. }));
+    assert_eq!(hydra.calls.load(Ordering::SeqCst), 2);
+  }
+}
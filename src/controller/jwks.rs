@@ -0,0 +1,438 @@
+use std::{fs, io::Error};
+
+use jsonwebtoken::{
+  decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Header, Validation,
+};
+use megacommerce_proto::JwtClaims;
+use megacommerce_shared::{
+  models::errors::{BoxedErr, ErrorType, InternalError},
+  utils::time::time_get_seconds,
+};
+use reqwest::Client;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{Map, Value as JsonValue};
+use tokio::sync::{Mutex, RwLock};
+use tonic::async_trait;
+
+use crate::{
+  models::config::{ClaimsConfig, JwksConfig},
+  utils::claims::claims_from_token_payload,
+};
+
+use super::hydra::{HydraClient, HydraValidation};
+
+/// Verifies JWT access tokens locally, against the signing keys hydra publishes,
+/// the keys are fetched once, and refetched when a token is signed by an unknown key
+/// (key rotation), or when the cached keys are older than `JwksConfig::cache_ttl_secs`,
+/// a single fetch runs at a time, and the fetches (failed ones too) are at most once per
+/// `JwksConfig::refresh_interval_secs`, the `iss` and `aud` are checked against
+/// `ClaimsConfig`, like the forwarded claims
+#[derive(Debug)]
+pub struct JwksHydraClient {
+  pub config: JwksConfig,
+  pub issuers: Vec<String>,
+  pub audiences: Vec<String>,
+  pub jwks_url: String,
+  pub http: Client,
+  keys: RwLock<CachedKeys>,
+  // held by the request fetching the keys, not while reading them
+  fetching: Mutex<()>,
+}
+
+#[derive(Debug)]
+struct CachedKeys {
+  set: JwkSet,
+  fetched_at: i64,
+  /// the last fetch, successful or not
+  attempted_at: i64,
+  /// the error of the last fetch, if it failed
+  error: Option<String>,
+}
+
+/// The lookup of a key in the cached keys
+enum CachedKey {
+  Found(DecodingKey),
+  /// the key is unknown, and the keys were fetched within the refresh interval
+  Unknown,
+  /// the key is unknown or expired, and the keys can be fetched
+  Fetch,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+  One(String),
+  Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+struct AccessTokenClaims {
+  #[serde(default)]
+  sub: String,
+  #[serde(default)]
+  jti: String,
+  #[serde(default)]
+  exp: i64,
+  #[serde(default)]
+  client_id: String,
+  aud: Option<Audience>,
+  /// hydra puts the granted scopes in `scp`, other issuers use a space delimited `scope`
+  #[serde(default)]
+  scp: Vec<String>,
+  scope: Option<String>,
+}
+
+impl JwksHydraClient {
+  pub fn new(config: JwksConfig, claims: &ClaimsConfig, hydra_url: &str, http: Client) -> Self {
+    let jwks_url = config
+      .url
+      .clone()
+      .unwrap_or_else(|| format!("{}/.well-known/jwks.json", hydra_url.trim_end_matches('/')));
+    let keys = RwLock::new(CachedKeys {
+      set: JwkSet { keys: vec![] },
+      fetched_at: 0,
+      attempted_at: 0,
+      error: None,
+    });
+    let (issuers, audiences) = (claims.issuers.clone(), claims.audiences.clone());
+    Self { config, issuers, audiences, jwks_url, http, keys, fetching: Mutex::new(()) }
+  }
+
+  /// Checks if the token looks like a JWT, an opaque token must be introspected
+  pub fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+  }
+
+  async fn fetch_keys(&self) -> Result<JwkSet, BoxedErr> {
+    let path = "auth.controller.jwks_fetch_keys";
+    let ie = |err: BoxedErr, msg: &str| {
+      Box::new(InternalError::new(path.into(), err, ErrorType::Internal, false, msg.into()))
+    };
+
+    if let Some(file) = &self.config.file {
+      let content = fs::read_to_string(file)
+        .map_err(|err| ie(Box::new(err), "failed to read the local jwks file"))?;
+      return Ok(
+        serde_json::from_str(&content)
+          .map_err(|err| ie(Box::new(err), "failed to parse the local jwks file"))?,
+      );
+    }
+
+    let resp = self
+      .http
+      .get(&self.jwks_url)
+      .send()
+      .await
+      .map_err(|err| ie(Box::new(err), "failed to request the hydra jwks"))?;
+
+    if !resp.status().is_success() {
+      let msg = format!("failed to request the hydra jwks: {}", resp.status());
+      return Err(ie(Box::new(Error::other(msg.clone())), &msg));
+    }
+
+    Ok(resp.json().await.map_err(|err| ie(Box::new(err), "failed to parse the hydra jwks"))?)
+  }
+
+  /// Returns the decoding key for the token header, refetching the key set if the key
+  /// id is unknown, or the keys expired
+  async fn key_for(&self, header: &Header) -> Result<Option<DecodingKey>, BoxedErr> {
+    let kid = header.kid.clone().unwrap_or_default();
+    match self.cached_key(&kid).await? {
+      CachedKey::Found(key) => return Ok(Some(key)),
+      CachedKey::Unknown => return Ok(None),
+      CachedKey::Fetch => {}
+    }
+
+    let _fetching = self.fetching.lock().await;
+    // another request might have fetched the keys while waiting for the lock
+    match self.cached_key(&kid).await? {
+      CachedKey::Found(key) => return Ok(Some(key)),
+      CachedKey::Unknown => return Ok(None),
+      CachedKey::Fetch => {}
+    }
+
+    let res = self.fetch_keys().await;
+    let mut keys = self.keys.write().await;
+    keys.attempted_at = time_get_seconds() as i64;
+    match res {
+      Ok(set) => {
+        keys.set = set;
+        keys.fetched_at = keys.attempted_at;
+        keys.error = None;
+        Ok(keys.set.find(&kid).and_then(|jwk| DecodingKey::from_jwk(jwk).ok()))
+      }
+      Err(err) => {
+        keys.error = Some(err.to_string());
+        Err(err)
+      }
+    }
+  }
+
+  /// Looks the key up in the cached keys, within the refresh interval of a failed fetch,
+  /// an unknown or expired key fails with the fetch error
+  async fn cached_key(&self, kid: &str) -> Result<CachedKey, BoxedErr> {
+    let now = time_get_seconds() as i64;
+    let keys = self.keys.read().await;
+    let fresh = now - keys.fetched_at <= self.config.cache_ttl_secs as i64;
+    let can_fetch = now - keys.attempted_at >= self.config.refresh_interval_secs as i64;
+
+    let jwk = keys.set.find(kid);
+    match jwk {
+      // an expired key is still used until it can be refetched
+      Some(jwk) if fresh || (!can_fetch && keys.error.is_none()) => {
+        Ok(DecodingKey::from_jwk(jwk).map(CachedKey::Found).unwrap_or(CachedKey::Unknown))
+      }
+      _ if can_fetch => Ok(CachedKey::Fetch),
+      _ => match &keys.error {
+        Some(msg) => {
+          let path = "auth.controller.jwks_key_for".to_string();
+          let msg = format!("the last jwks fetch failed: {}", msg);
+          let err = Box::new(Error::other(msg.clone()));
+          Err(Box::new(InternalError::new(path, err, ErrorType::Internal, true, msg)))
+        }
+        None => Ok(CachedKey::Unknown),
+      },
+    }
+  }
+
+  /// Verifies the token, and returns its claims, None if it's invalid
+  pub async fn verified_claims(&self, token: &str) -> Result<Option<JwtClaims>, BoxedErr> {
+    let payload = self.verify::<Map<String, JsonValue>>(token).await?;
+    Ok(payload.ok().map(|payload| claims_from_token_payload(&payload)))
+  }
+
+  /// Verifies the token signature and its registered claims, and decodes its payload,
+  /// the inner error is why the token is invalid
+  async fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<Result<T, String>, BoxedErr> {
+    let invalid = |msg: String| Ok(Err(msg));
+
+    let header = match decode_header(token) {
+      Ok(header) => header,
+      Err(err) => return invalid(format!("malformed token header: {}", err)),
+    };
+
+    // only asymmetric signatures can be verified with the published keys
+    if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+      return invalid(format!("unsupported token algorithm: {:?}", header.alg));
+    }
+
+    let key = match self.key_for(&header).await? {
+      Some(key) => key,
+      None => return invalid(format!("unknown token signing key: {:?}", header.kid)),
+    };
+
+    let mut validation = Validation::new(header.alg);
+    validation.leeway = self.config.leeway_secs;
+    validation.validate_nbf = true;
+    validation.validate_aud = !self.audiences.is_empty();
+    if validation.validate_aud {
+      validation.set_audience(&self.audiences);
+    }
+    if !self.issuers.is_empty() {
+      validation.set_issuer(&self.issuers);
+    }
+
+    match decode::<T>(token, &key, &validation) {
+      Ok(data) => Ok(Ok(data.claims)),
+      Err(err) => invalid(format!("the token is invalid: {}", err)),
+    }
+  }
+}
+
+#[async_trait]
+impl HydraClient for JwksHydraClient {
+  async fn validate_token(&self, token: &str) -> Result<HydraValidation, BoxedErr> {
+    let claims = match self.verify::<AccessTokenClaims>(token).await? {
+      Ok(claims) => claims,
+      Err(msg) => return Ok(HydraValidation::Invalid(msg)),
+    };
+
+    let scopes = match claims.scope {
+      Some(scope) if claims.scp.is_empty() => scope.split_whitespace().map(String::from).collect(),
+      _ => claims.scp,
+    };
+    let aud = match claims.aud {
+      Some(Audience::One(aud)) => vec![aud],
+      Some(Audience::Many(aud)) => aud,
+      None => vec![],
+    };
+
+    Ok(HydraValidation::Valid {
+      sub: claims.sub,
+      jti: claims.jti,
+      exp: claims.exp,
+      scopes,
+      client_id: claims.client_id,
+      aud,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env, fs, path::PathBuf};
+
+  use jsonwebtoken::{encode, EncodingKey};
+  use serde_json::json;
+
+  use super::*;
+
+  const ISSUER: &str = "http://127.0.0.1:4444/";
+  const AUDIENCE: &str = "megacommerce";
+  const K1: &[u8] = include_bytes!("../../testdata/jwks/k1.pem");
+  const K2: &[u8] = include_bytes!("../../testdata/jwks/k2.pem");
+
+  fn fixture(name: &str) -> String {
+    format!("{}/testdata/jwks/{}", env!("CARGO_MANIFEST_DIR"), name)
+  }
+
+  /// A copy of the fixture, so the test can rotate the keys
+  fn jwks_file(test: &str, fixture_name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("auth-jwks-{}-{}.json", test, std::process::id()));
+    fs::copy(fixture(fixture_name), &path).unwrap();
+    path
+  }
+
+  fn client(file: &str, refresh_interval_secs: u64) -> JwksHydraClient {
+    let config = JwksConfig {
+      enabled: true,
+      file: Some(file.into()),
+      leeway_secs: 0,
+      cache_ttl_secs: 3600,
+      refresh_interval_secs,
+      ..Default::default()
+    };
+    let claims = ClaimsConfig {
+      issuers: vec![ISSUER.into()],
+      audiences: vec![AUDIENCE.into()],
+      ..Default::default()
+    };
+    JwksHydraClient::new(config, &claims, "http://127.0.0.1:4444", Client::new())
+  }
+
+  fn token(pem: &[u8], kid: &str, iss: &str, aud: &str, exp_in: i64) -> String {
+    let now = time_get_seconds() as i64;
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(kid.into());
+    let claims = json!({
+      "iss": iss,
+      "sub": "user-1",
+      "aud": [aud],
+      "exp": now + exp_in,
+      "nbf": now - 10,
+      "iat": now - 10,
+      "jti": "jti-1",
+      "client_id": "web",
+      "scp": ["offline", "products.read"],
+    });
+    encode(&header, &claims, &EncodingKey::from_rsa_pem(pem).unwrap()).unwrap()
+  }
+
+  async fn validate(client: &JwksHydraClient, token: &str) -> HydraValidation {
+    client.validate_token(token).await.unwrap()
+  }
+
+  #[tokio::test]
+  async fn accepts_a_valid_token() {
+    let client = client(&fixture("jwks.json"), 30);
+    match validate(&client, &token(K1, "k1", ISSUER, AUDIENCE, 300)).await {
+      HydraValidation::Valid { sub, jti, scopes, client_id, aud, .. } => {
+        assert_eq!(sub, "user-1");
+        assert_eq!(jti, "jti-1");
+        assert_eq!(scopes, vec!["offline", "products.read"]);
+        assert_eq!(client_id, "web");
+        assert_eq!(aud, vec![AUDIENCE]);
+      }
+      invalid => panic!("expected a valid token, got {:?}", invalid),
+    }
+  }
+
+  #[tokio::test]
+  async fn rejects_an_expired_token() {
+    let client = client(&fixture("jwks.json"), 30);
+    let res = validate(&client, &token(K1, "k1", ISSUER, AUDIENCE, -60)).await;
+    assert!(matches!(res, HydraValidation::Invalid(msg) if msg.contains("ExpiredSignature")));
+  }
+
+  #[tokio::test]
+  async fn rejects_an_untrusted_issuer() {
+    let client = client(&fixture("jwks.json"), 30);
+    let res = validate(&client, &token(K1, "k1", "http://evil/", AUDIENCE, 300)).await;
+    assert!(matches!(res, HydraValidation::Invalid(msg) if msg.contains("InvalidIssuer")));
+  }
+
+  #[tokio::test]
+  async fn rejects_an_unaccepted_audience() {
+    let client = client(&fixture("jwks.json"), 30);
+    let res = validate(&client, &token(K1, "k1", ISSUER, "other", 300)).await;
+    assert!(matches!(res, HydraValidation::Invalid(msg) if msg.contains("InvalidAudience")));
+  }
+
+  #[tokio::test]
+  async fn rejects_a_token_signed_by_another_key() {
+    let client = client(&fixture("jwks.json"), 30);
+    // signed by k2, but claims to be signed by k1
+    let res = validate(&client, &token(K2, "k1", ISSUER, AUDIENCE, 300)).await;
+    assert!(matches!(res, HydraValidation::Invalid(msg) if msg.contains("InvalidSignature")));
+  }
+
+  #[tokio::test]
+  async fn reads_the_claims_of_a_verified_token() {
+    let client = client(&fixture("jwks.json"), 30);
+    let claims = client.verified_claims(&token(K1, "k1", ISSUER, AUDIENCE, 300)).await.unwrap();
+    let claims = claims.expect("expected the claims of a valid token");
+    assert_eq!((claims.sub.as_str(), claims.jti.as_str()), ("user-1", "jti-1"));
+    assert_eq!(claims.aud, vec![AUDIENCE]);
+    assert!(claims.custom.contains_key("client_id"));
+
+    let forged = client.verified_claims(&token(K2, "k1", ISSUER, AUDIENCE, 300)).await.unwrap();
+    assert!(forged.is_none());
+  }
+
+  #[tokio::test]
+  async fn refetches_the_keys_on_a_rotated_kid() {
+    let file = jwks_file("rotated", "jwks.json");
+    let client = client(file.to_str().unwrap(), 0);
+
+    let k1 = token(K1, "k1", ISSUER, AUDIENCE, 300);
+    let k2 = token(K2, "k2", ISSUER, AUDIENCE, 300);
+    assert!(matches!(validate(&client, &k1).await, HydraValidation::Valid { .. }));
+    assert!(matches!(validate(&client, &k2).await, HydraValidation::Invalid(_)));
+
+    fs::copy(fixture("jwks_rotated.json"), &file).unwrap();
+    assert!(matches!(validate(&client, &k2).await, HydraValidation::Valid { .. }));
+    assert!(matches!(validate(&client, &k1).await, HydraValidation::Valid { .. }));
+    fs::remove_file(file).ok();
+  }
+
+  #[tokio::test]
+  async fn refetches_at_most_once_per_refresh_interval() {
+    let file = jwks_file("interval", "jwks.json");
+    let client = client(file.to_str().unwrap(), 60);
+
+    let k1 = token(K1, "k1", ISSUER, AUDIENCE, 300);
+    let k2 = token(K2, "k2", ISSUER, AUDIENCE, 300);
+    assert!(matches!(validate(&client, &k1).await, HydraValidation::Valid { .. }));
+
+    // the unknown kid doesn't refetch the keys, they were just fetched
+    fs::copy(fixture("jwks_rotated.json"), &file).unwrap();
+    assert!(matches!(validate(&client, &k2).await, HydraValidation::Invalid(_)));
+    fs::remove_file(file).ok();
+  }
+
+  #[tokio::test]
+  async fn backs_off_after_a_failed_fetch() {
+    let file = env::temp_dir().join(format!("auth-jwks-missing-{}.json", std::process::id()));
+    fs::remove_file(&file).ok();
+    let client = client(file.to_str().unwrap(), 60);
+
+    let k1 = token(K1, "k1", ISSUER, AUDIENCE, 300);
+    assert!(client.validate_token(&k1).await.is_err());
+
+    // the failure is kept until the refresh interval passes, without refetching
+    fs::copy(fixture("jwks.json"), &file).unwrap();
+    let err = client.validate_token(&k1).await.unwrap_err();
+    assert!(err.to_string().contains("the last jwks fetch failed"));
+    fs::remove_file(file).ok();
+  }
+}
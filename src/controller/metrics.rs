@@ -0,0 +1,188 @@
+use std::{
+  collections::BTreeMap,
+  fmt::Write,
+  io,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::TcpListener,
+  spawn,
+  sync::Semaphore,
+  time::timeout,
+};
+
+use super::breaker::CircuitBreaker;
+
+/// In-process counters and gauges, rendered in the prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics {
+  counters: Mutex<BTreeMap<String, u64>>,
+  gauges: Mutex<BTreeMap<String, i64>>,
+}
+
+impl Metrics {
+  pub fn incr(&self, name: &str, labels: &[(&str, &str)]) {
+    let mut counters = self.counters.lock().unwrap();
+    *counters.entry(series(name, labels)).or_default() += 1;
+  }
+
+  pub fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: i64) {
+    self.gauges.lock().unwrap().insert(series(name, labels), value);
+  }
+
+  /// Renders the series grouped by metric, each metric with its `# HELP` and `# TYPE` lines
+  pub fn render(&self) -> String {
+    let mut out = String::new();
+    let counters = self.counters.lock().unwrap().clone();
+    let gauges = self.gauges.lock().unwrap().clone();
+    render_family(&mut out, "counter", &counters);
+    render_family(&mut out, "gauge", &gauges);
+    out
+  }
+}
+
+/// The help lines of the metrics, a metric missing here is rendered without one
+const HELP: [(&str, &str); 15] = [
+  ("auth_claims_rejections_total", "Tokens rejected by the local claims checks"),
+  ("auth_degraded_decisions_total", "Decisions taken while hydra is unreachable"),
+  ("auth_device_mismatches_total", "Tokens used from another device than they're bound to"),
+  ("auth_hydra_circuit_open", "Whether the hydra circuit is open"),
+  ("auth_hydra_requests_total", "Requests to hydra, by result"),
+  ("auth_introspections_coalesced_total", "Introspections answered by an in-flight one"),
+  ("auth_local_revocation_hits_total", "Tokens denied by the local revocations cache"),
+  ("auth_local_revocations", "Entries in the local revocations cache"),
+  ("auth_refresh_ahead_inflight", "Token statuses being refreshed ahead of expiry"),
+  ("auth_refresh_ahead_total", "Refresh-ahead introspections, by result"),
+  ("auth_session_idle_timeouts_total", "Sessions terminated for going idle"),
+  ("auth_session_limit_total", "Sessions over the concurrent sessions limit of their user"),
+  ("auth_shadow_decisions_total", "Decisions of the shadow rules and policies"),
+  ("auth_unmatched_routes_total", "Requests not matching any route entry"),
+  ("auth_user_not_before_events_total", "User events that invalidated the issued tokens"),
+];
+
+fn render_family<T: std::fmt::Display>(out: &mut String, kind: &str, series: &BTreeMap<String, T>) {
+  // the series are sorted, but E,g `a{..}` sorts after `a_b`, so they're grouped by name
+  let mut families: BTreeMap<&str, Vec<(&String, &T)>> = BTreeMap::new();
+  for (key, value) in series {
+    let name = key.split('{').next().unwrap_or_default();
+    families.entry(name).or_default().push((key, value));
+  }
+
+  for (name, series) in families {
+    if let Some((_, help)) = HELP.iter().find(|(n, _)| *n == name) {
+      let _ = writeln!(out, "# HELP {} {}", name, help);
+    }
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (key, value) in series {
+      let _ = writeln!(out, "{} {}", key, value);
+    }
+  }
+}
+
+// E,g: auth_unmatched_routes_total{method="GET",path="/api/{id}",policy="deny_not_found"}
+fn series(name: &str, labels: &[(&str, &str)]) -> String {
+  if labels.is_empty() {
+    return name.to_string();
+  }
+
+  let labels = labels
+    .iter()
+    .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+    .collect::<Vec<_>>()
+    .join(",");
+  format!("{}{{{}}}", name, labels)
+}
+
+/// The concurrent connections of the metrics server, the extra ones wait to be accepted
+const MAX_CONNECTIONS: usize = 32;
+/// Bounds reading the request and writing the response, so a slow client can't hold a connection
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves the rendered metrics as a plain text response on `GET /metrics`, and the service
+/// health (with the hydra circuit state) as a JSON response on `GET /health`, only the
+/// request line is read, anything else gets a 404 or a 405
+pub(super) async fn serve_metrics(
+  metrics: Arc<Metrics>,
+  breaker: Arc<CircuitBreaker>,
+  addr: &str,
+) -> Result<(), io::Error> {
+  let listener = TcpListener::bind(addr).await?;
+  let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+  spawn(async move {
+    loop {
+      let Ok(permit) = connections.clone().acquire_owned().await else {
+        return;
+      };
+      let Ok((mut socket, _)) = listener.accept().await else {
+        continue;
+      };
+
+      let (metrics, breaker) = (metrics.clone(), breaker.clone());
+      spawn(async move {
+        let _permit = permit;
+        let mut buf = [0u8; 1024];
+        let Ok(Ok(n)) = timeout(IO_TIMEOUT, socket.read(&mut buf)).await else {
+          return;
+        };
+        // E,g: GET /health HTTP/1.1
+        let req = String::from_utf8_lossy(&buf[..n]);
+        let mut line = req.lines().next().unwrap_or_default().split_whitespace();
+        let (method, target) = (line.next().unwrap_or_default(), line.next().unwrap_or_default());
+        let path = target.split('?').next().unwrap_or_default();
+
+        let (status, content_type, body) = match (method, path) {
+          ("GET", "/metrics") => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+          ("GET", "/health") => {
+            let body = format!(r#"{{"status":"ok","hydra_circuit":"{}"}}"#, breaker.state());
+            ("200 OK", "application/json", body)
+          }
+          (_, "/metrics" | "/health") => {
+            ("405 Method Not Allowed", "text/plain", "method not allowed\n".to_string())
+          }
+          _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        };
+        let res = format!(
+          "HTTP/1.1 {}\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+          status,
+          content_type,
+          body.len(),
+          body
+        );
+        let _ = timeout(IO_TIMEOUT, socket.write_all(res.as_bytes())).await;
+      });
+    }
+  });
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn renders_the_series_grouped_by_metric() {
+    let metrics = Metrics::default();
+    metrics.incr("auth_refresh_ahead_total", &[("result", "ok")]);
+    metrics.incr("auth_refresh_ahead_total", &[("result", "dropped")]);
+    metrics.incr("auth_refresh_ahead_total", &[("result", "ok")]);
+    metrics.set_gauge("auth_refresh_ahead_inflight", &[], 3);
+    metrics.incr("auth_custom_total", &[]);
+
+    let expected = [
+      "# TYPE auth_custom_total counter",
+      "auth_custom_total 1",
+      "# HELP auth_refresh_ahead_total Refresh-ahead introspections, by result",
+      "# TYPE auth_refresh_ahead_total counter",
+      r#"auth_refresh_ahead_total{result="dropped"} 1"#,
+      r#"auth_refresh_ahead_total{result="ok"} 2"#,
+      "# HELP auth_refresh_ahead_inflight Token statuses being refreshed ahead of expiry",
+      "# TYPE auth_refresh_ahead_inflight gauge",
+      "auth_refresh_ahead_inflight 3",
+    ];
+    assert_eq!(metrics.render().lines().collect::<Vec<_>>(), expected);
+  }
+}
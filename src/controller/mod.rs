@@ -1,32 +1,57 @@
+mod access;
+mod admin;
 mod audit;
+mod breaker;
+mod claims;
 mod hydra;
+mod introspection;
+mod jwks;
+mod metrics;
+mod policy;
+mod rbac;
 mod redis;
+mod refresh;
 mod response;
+mod revocations;
 mod router;
 mod routes;
+mod session;
 mod token;
 mod user_cache;
+mod validation;
 
-use std::net::SocketAddr;
+use std::{io::Error, net::SocketAddr, sync::Arc, time::Duration};
 
+use breaker::{CircuitBreaker, ResilientHydraClient};
 use deadpool_redis::Pool as RedisPool;
-use hydra::DefaultHydraClient;
+use hydra::{DefaultHydraClient, HydraClient};
+use introspection::Introspector;
+use jwks::JwksHydraClient;
 use megacommerce_proto::service::auth::v3::authorization_server::AuthorizationServer;
 use megacommerce_proto::Config;
 use megacommerce_shared::models::errors::{BoxedErr, ErrorType, InternalError};
 use megacommerce_shared::models::r_lock::RLock;
 use megacommerce_shared::utils::middleware::middleware_context;
+use metrics::{serve_metrics, Metrics};
+use policy::PolicySet;
 use redis::DefaultRedisClient;
+use refresh::Refresher;
 use reqwest::Client;
+use revocations::{watch_revocations, watch_user_events, RevocationCache};
+use routes::{load_route_table, watch_route_table, RouteTable, SharedRouteTable};
+use tokio::sync::RwLock;
 use tonic::service::InterceptorLayer;
 use tonic::transport::Server as TonicServer;
 use tower::ServiceBuilder;
 
+use crate::models::config::Config as ServiceConfig;
+use crate::proto::auth_admin::auth_admin_service_server::AuthAdminServiceServer;
 use crate::store::database::AuthStore;
 use crate::utils::net::validate_url_target;
 
 pub struct ControllerArgs {
   pub config: RLock<Config>,
+  pub service_config: ServiceConfig,
   pub redis_con: RLock<RedisPool>,
   pub store: RLock<dyn AuthStore + Send + Sync>,
 }
@@ -34,10 +59,20 @@ pub struct ControllerArgs {
 #[derive(Debug)]
 pub struct Controller {
   pub config: RLock<Config>,
-  pub hydra: DefaultHydraClient,
+  pub service_config: ServiceConfig,
+  /// a trait object, so a stub client can stand in for hydra
+  pub hydra: Arc<dyn HydraClient>,
+  pub jwks: Option<JwksHydraClient>,
   pub redis: DefaultRedisClient,
   pub redis_con: RLock<RedisPool>,
   pub(super) store: RLock<dyn AuthStore + Send + Sync>,
+  routes: SharedRouteTable,
+  policies: PolicySet,
+  introspector: Arc<Introspector>,
+  refresher: Arc<Refresher>,
+  revocations: Arc<RevocationCache>,
+  metrics: Arc<Metrics>,
+  breaker: Arc<CircuitBreaker>,
 
   pub cached_config: CachedConfig,
 }
@@ -59,14 +94,37 @@ impl Controller {
       (hydra, id, secret)
     };
 
+    let hydra_cfg = &ca.service_config.hydra;
+    let http = Client::builder()
+      .connect_timeout(Duration::from_millis(hydra_cfg.connect_timeout_ms))
+      .timeout(Duration::from_millis(hydra_cfg.request_timeout_ms))
+      .build()
+      .expect("failed to build the hydra http client");
+
     let hydra = DefaultHydraClient {
       hydra_url: urls.0,
-      http: Client::new(),
+      http: http.clone(),
       client_id: urls.1,
       client_secret: urls.2,
     };
 
-    let redis = DefaultRedisClient { redis: ca.redis_con.clone() };
+    let (jwks, claims) = (ca.service_config.jwks.clone(), &ca.service_config.claims);
+    let jwks = jwks.enabled.then(|| JwksHydraClient::new(jwks, claims, &hydra.hydra_url, http));
+
+    let metrics = Arc::new(Metrics::default());
+    let breaker = Arc::new(CircuitBreaker::new(
+      hydra_cfg.breaker_failure_threshold,
+      hydra_cfg.breaker_open_secs,
+      metrics.clone(),
+    ));
+    let hydra: Arc<dyn HydraClient> =
+      Arc::new(ResilientHydraClient::new(hydra, hydra_cfg, breaker.clone()));
+
+    let tokens = ca.service_config.tokens.clone();
+    let redis = DefaultRedisClient { redis: ca.redis_con.clone(), tokens };
+    let introspector = Arc::new(Introspector::new(hydra.clone(), redis.clone(), metrics.clone()));
+    let concurrency = ca.service_config.tokens.refresh_concurrency;
+    let refresher = Refresher::new(introspector.clone(), concurrency, metrics.clone());
     let cfg = ca.config.get().await.localization.clone().unwrap();
     let cached_config = CachedConfig {
       available_languages: cfg.available_locales.clone(),
@@ -74,20 +132,82 @@ impl Controller {
     };
     Self {
       config: ca.config,
+      service_config: ca.service_config,
       hydra,
+      jwks,
       redis,
       redis_con: ca.redis_con,
       store: ca.store,
+      routes: Arc::new(RwLock::new(Arc::new(RouteTable::default()))),
+      policies: PolicySet::default(),
+      introspector,
+      refresher: Arc::new(refresher),
+      revocations: Arc::new(RevocationCache::default()),
+      metrics,
+      breaker,
       cached_config,
     }
   }
 
-  pub async fn run(self) -> Result<(), BoxedErr> {
-    let url = {
+  /// A controller validating tokens against `hydra`, its redis and database are never
+  /// reachable, so it only serves the tests of decisions that don't need them
+  #[cfg(test)]
+  pub(super) fn stub(service_config: ServiceConfig, hydra: Arc<dyn HydraClient>) -> Self {
+    Self::stub_on(service_config, hydra, "redis://127.0.0.1:1")
+  }
+
+  /// Same as `stub`, with its redis at `redis_url`
+  #[cfg(test)]
+  pub(super) fn stub_on(
+    service_config: ServiceConfig,
+    hydra: Arc<dyn HydraClient>,
+    redis_url: &str,
+  ) -> Self {
+    use deadpool_redis::{Config as RedisConfig, Runtime};
+    use sqlx::postgres::PgPoolOptions;
+
+    use crate::store::pg_impl::{AuthStoreImpl, AuthStoreImplArgs};
+
+    let redis_con = RedisConfig::from_url(redis_url).create_pool(Some(Runtime::Tokio1)).unwrap();
+    let redis_con = RLock(Arc::new(RwLock::new(redis_con)));
+    let db = PgPoolOptions::new().connect_lazy("postgres://127.0.0.1:1/auth").unwrap();
+    let store = AuthStoreImpl::new(AuthStoreImplArgs { db: RLock(Arc::new(RwLock::new(db))) });
+
+    let metrics = Arc::new(Metrics::default());
+    let breaker = Arc::new(CircuitBreaker::new(1, 30, metrics.clone()));
+    let tokens = service_config.tokens.clone();
+    let redis = DefaultRedisClient { redis: redis_con.clone(), tokens };
+    let introspector = Arc::new(Introspector::new(hydra.clone(), redis.clone(), metrics.clone()));
+    let refresher = Refresher::new(introspector.clone(), 1, metrics.clone());
+    Self {
+      config: RLock(Arc::new(RwLock::new(Config::default()))),
+      service_config,
+      hydra,
+      jwks: None,
+      redis,
+      redis_con,
+      store: RLock(Arc::new(RwLock::new(store))),
+      routes: Arc::new(RwLock::new(Arc::new(RouteTable::default()))),
+      policies: PolicySet::default(),
+      introspector,
+      refresher: Arc::new(refresher),
+      revocations: Arc::new(RevocationCache::default()),
+      metrics,
+      breaker,
+      cached_config: CachedConfig::default(),
+    }
+  }
+
+  pub async fn run(mut self) -> Result<(), BoxedErr> {
+    let (url, metrics_addr, redis_url) = {
       let config = self.config.get().await;
-      config.services.as_ref().unwrap().auth_service_grpc_url().to_owned()
+      let url = config.services.as_ref().unwrap().auth_service_grpc_url().to_owned();
+      let metrics = config.metrics.as_ref().filter(|m| m.enable()).map(|m| m.listen_address());
+      let redis_url = config.cache.as_ref().unwrap().redis_address().to_owned();
+      (url, metrics.map(String::from), redis_url)
     };
 
+    // the config is validated before starting any background task
     validate_url_target(&url).map_err(|e| {
       Box::new(InternalError {
         temp: false,
@@ -98,10 +218,72 @@ impl Controller {
       })
     })?;
 
+    let admin = self.service_config.admin.enabled;
+    if admin && self.service_config.admin.scopes.is_empty() {
+      return Err(Box::new(InternalError {
+        temp: false,
+        err: Box::new(Error::other("admin.scopes is empty")),
+        err_type: ErrorType::ConfigError,
+        msg: "the admin service is enabled without admin scopes, any token would be an admin"
+          .into(),
+        path: "auth.controller.run".into(),
+      }));
+    }
+
+    if self.service_config.tokens.max_token_lifetime_secs == 0 {
+      return Err(Box::new(InternalError {
+        temp: false,
+        err: Box::new(Error::other("tokens.max_token_lifetime_secs is 0")),
+        err_type: ErrorType::ConfigError,
+        msg: "the max token lifetime is required, the revocations would expire before the tokens"
+          .into(),
+        path: "auth.controller.run".into(),
+      }));
+    }
+
+    self.policies = PolicySet::new(self.service_config.policies.clone()).map_err(|e| {
+      Box::new(InternalError {
+        temp: false,
+        err: Box::new(e),
+        err_type: ErrorType::ConfigError,
+        msg: "failed to load the authorization policies".into(),
+        path: "auth.controller.run".into(),
+      })
+    })?;
+
+    let table = load_route_table(&self.service_config.routes, &self.store).await?;
+    *self.routes.write().await = Arc::new(table);
+    let store = RLock(self.store.0.clone());
+    watch_route_table(self.service_config.routes.clone(), store, self.routes.clone());
+
+    if !self.service_config.tokens.user_events_channel.is_empty() {
+      watch_user_events(redis_url.clone(), self.redis.clone(), self.metrics.clone());
+    }
+
+    if self.service_config.tokens.local_revocations {
+      let (redis, revocations) = (self.redis.clone(), self.revocations.clone());
+      watch_revocations(redis_url, redis, revocations, self.metrics.clone());
+    }
+
+    if let Some(addr) = metrics_addr {
+      serve_metrics(self.metrics.clone(), self.breaker.clone(), &addr).await.map_err(|e| {
+        Box::new(InternalError {
+          temp: false,
+          err: Box::new(e),
+          err_type: ErrorType::Internal,
+          msg: "failed to serve the auth service metrics".into(),
+          path: "auth.controller.run".into(),
+        })
+      })?;
+    }
+
+    let controller = Arc::new(self);
+
     let layer = ServiceBuilder::new().layer(InterceptorLayer::new(middleware_context)).into_inner();
     TonicServer::builder()
       .layer(layer)
-      .add_service(AuthorizationServer::new(self))
+      .add_service(AuthorizationServer::from_arc(controller.clone()))
+      .add_optional_service(admin.then(|| AuthAdminServiceServer::from_arc(controller)))
       .serve((url.parse::<SocketAddr>()).unwrap())
       .await?;
 
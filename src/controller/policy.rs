@@ -0,0 +1,319 @@
+use std::{
+  collections::HashMap,
+  io::{Error, ErrorKind},
+};
+
+use megacommerce_proto::{value::Kind, CachedUserData, JwtClaims, Value};
+
+use crate::{
+  models::policy::{Condition, ConditionOp, Policy},
+  utils::{matcher::RoutePattern, net::ip_in_cidr},
+};
+
+use super::access::Decision;
+
+/// The attributes a policy condition is evaluated against
+#[derive(Debug)]
+pub(super) struct PolicyAttributes<'a> {
+  pub path: &'a str,
+  pub method: &'a str,
+  pub ip: &'a str,
+  pub headers: &'a HashMap<String, String>,
+  /// the path params captured by the templated segments of the matched route
+  pub params: &'a HashMap<String, String>,
+  pub claims: &'a JwtClaims,
+  pub scopes: &'a [String],
+  pub user: Option<&'a CachedUserData>,
+}
+
+impl PolicyAttributes<'_> {
+  /// Resolves an attribute to its values, see `models::policy::Condition`
+  fn resolve(&self, attribute: &str) -> Vec<String> {
+    let one = |v: &str| if v.is_empty() { vec![] } else { vec![v.to_string()] };
+    let list = |v: &str| {
+      v.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect::<Vec<_>>()
+    };
+
+    match attribute {
+      "request.path" => one(self.path),
+      "request.method" => one(self.method),
+      "request.ip" => one(self.ip),
+      "claims.iss" => one(&self.claims.iss),
+      "claims.sub" => one(&self.claims.sub),
+      "claims.jti" => one(&self.claims.jti),
+      "claims.aud" => self.claims.aud.clone(),
+      "token.scopes" => self.scopes.to_vec(),
+      "user.roles" => self.user.map(|u| list(&u.roles)).unwrap_or_default(),
+      "user.props" => self.user.map(|u| list(&u.props)).unwrap_or_default(),
+      "user.is_oauth" => self.user.map(|u| vec![u.is_oauth.to_string()]).unwrap_or_default(),
+      attr => {
+        if let Some(name) = attr.strip_prefix("request.header.") {
+          self.headers.get(&name.to_lowercase()).map(|v| one(v)).unwrap_or_default()
+        } else if let Some(name) = attr.strip_prefix("request.params.") {
+          self.params.get(name).map(|v| one(v)).unwrap_or_default()
+        } else if let Some(key) = attr.strip_prefix("user.props.") {
+          // props are like: verified,theme:light, a prop without a value is a flag
+          let props = self.user.map(|u| list(&u.props)).unwrap_or_default();
+          props
+            .iter()
+            .map(|p| p.split_once(':').unwrap_or((p.as_str(), "true")))
+            .filter(|(k, _)| *k == key)
+            .map(|(_, v)| v.to_string())
+            .collect()
+        } else if let Some(name) = attr.strip_prefix("claims.") {
+          self.claims.custom.get(name).map(value_strings).unwrap_or_default()
+        } else {
+          vec![]
+        }
+      }
+    }
+  }
+}
+
+/// The attributes resolved by their full name, the rest are resolved by their prefix
+const ATTRIBUTES: [&str; 11] = [
+  "request.path",
+  "request.method",
+  "request.ip",
+  "claims.iss",
+  "claims.sub",
+  "claims.jti",
+  "claims.aud",
+  "token.scopes",
+  "user.roles",
+  "user.props",
+  "user.is_oauth",
+];
+const ATTRIBUTE_PREFIXES: [&str; 4] =
+  ["request.header.", "request.params.", "user.props.", "claims."];
+
+/// Checks if the attribute is one `PolicyAttributes::resolve` knows, so a typo fails
+/// loading the policies instead of silently resolving to an empty list
+fn known_attribute(attribute: &str) -> bool {
+  ATTRIBUTES.contains(&attribute)
+    || ATTRIBUTE_PREFIXES
+      .iter()
+      .any(|p| attribute.strip_prefix(p).is_some_and(|name| !name.is_empty()))
+}
+
+fn value_strings(value: &Value) -> Vec<String> {
+  match &value.kind {
+    Some(Kind::StringValue(s)) => vec![s.clone()],
+    Some(Kind::NumberValue(n)) => vec![n.to_string()],
+    Some(Kind::BoolValue(b)) => vec![b.to_string()],
+    Some(Kind::ListValue(l)) => l.values.iter().flat_map(value_strings).collect(),
+    _ => vec![],
+  }
+}
+
+fn condition_holds(cond: &Condition, attrs: &PolicyAttributes) -> bool {
+  let values = attrs.resolve(&cond.attribute);
+  let listed = |v: &String| cond.values.contains(v);
+
+  match cond.op {
+    ConditionOp::AnyOf => values.iter().any(listed),
+    ConditionOp::NoneOf => !values.iter().any(listed),
+    ConditionOp::AllOf => cond.values.iter().all(|v| values.contains(v)),
+    ConditionOp::Prefix => {
+      values.iter().any(|v| cond.values.iter().any(|p| v.starts_with(p.as_str())))
+    }
+    ConditionOp::InCidr => values.iter().any(|v| cond.values.iter().any(|c| ip_in_cidr(v, c))),
+    ConditionOp::Present => !values.is_empty(),
+    ConditionOp::Absent => values.is_empty(),
+  }
+}
+
+/// The configured policies, with their route patterns compiled
+#[derive(Debug, Default)]
+pub(super) struct PolicySet {
+  policies: Vec<(Vec<RoutePattern>, Policy)>,
+}
+
+impl PolicySet {
+  pub fn new(policies: Vec<Policy>) -> Result<Self, Error> {
+    let policies = policies
+      .into_iter()
+      .map(|p| {
+        let unknown = p.when.iter().chain(&p.require).find(|c| !known_attribute(&c.attribute));
+        if let Some(cond) = unknown {
+          let msg = format!("policy {}: unknown attribute {:?}", p.name, cond.attribute);
+          return Err(Error::new(ErrorKind::InvalidInput, msg));
+        }
+
+        let routes =
+          p.routes.iter().map(|r| RoutePattern::parse(r)).collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, Error>((routes, p))
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Self { policies })
+  }
+
+  /// Returns the policies having a route that matches the path
+  pub fn applicable(&self, path: &str) -> Vec<&Policy> {
+    let path = path.split('?').next().unwrap_or_default();
+    self
+      .policies
+      .iter()
+      .filter(|(routes, _)| routes.iter().any(|r| r.matches(path).is_some()))
+      .map(|(_, policy)| policy)
+      .collect()
+  }
+}
+
+/// Evaluates the applicable enforced policies, the first policy with a failed requirement denies
+pub(super) fn evaluate_policies(policies: &[&Policy], attrs: &PolicyAttributes) -> Decision {
+  policies
+    .iter()
+    .filter(|p| !p.shadow)
+    .map(|p| evaluate_policy(p, attrs))
+    .find(|d| *d != Decision::Allow)
+    .unwrap_or(Decision::Allow)
+}
+
+/// Evaluates a single policy, a policy that doesn't apply to the attributes allows
+pub(super) fn evaluate_policy(policy: &Policy, attrs: &PolicyAttributes) -> Decision {
+  if !policy.when.iter().all(|c| condition_holds(c, attrs)) {
+    return Decision::Allow;
+  }
+
+  match policy.require.iter().find(|c| !condition_holds(c, attrs)) {
+    Some(failed) => {
+      let reason = policy.reason.clone().unwrap_or_else(|| {
+        format!("{} {:?} {:?} doesn't hold", failed.attribute, failed.op, failed.values)
+      });
+      Decision::Deny { rule: format!("policy:{}", policy.name), reason }
+    }
+    None => Decision::Allow,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn cond(attribute: &str, op: ConditionOp, values: &[&str]) -> Condition {
+    let values = values.iter().map(|v| v.to_string()).collect();
+    Condition { attribute: attribute.into(), op, values }
+  }
+
+  fn holds(cond: &Condition, user: Option<&CachedUserData>) -> bool {
+    let headers = HashMap::from([("x-tenant".to_string(), "acme".to_string())]);
+    let params = HashMap::from([("id".to_string(), "42".to_string())]);
+    let claims = JwtClaims { sub: "user-1".into(), ..Default::default() };
+    let scopes = ["products:read".to_string(), "products:write".to_string()];
+    let attrs = PolicyAttributes {
+      path: "/api/v1/products/42",
+      method: "PUT",
+      ip: "10.1.2.3",
+      headers: &headers,
+      params: &params,
+      claims: &claims,
+      scopes: &scopes,
+      user,
+    };
+    condition_holds(cond, &attrs)
+  }
+
+  #[test]
+  fn evaluates_the_condition_ops() {
+    use ConditionOp::*;
+
+    let cases = [
+      (cond("request.method", AnyOf, &["GET", "PUT"]), true),
+      (cond("request.method", AnyOf, &["GET"]), false),
+      (cond("request.method", NoneOf, &["DELETE"]), true),
+      (cond("request.method", NoneOf, &["PUT"]), false),
+      (cond("token.scopes", AllOf, &["products:read", "products:write"]), true),
+      (cond("token.scopes", AllOf, &["products:read", "products:delete"]), false),
+      (cond("request.path", Prefix, &["/api/v1/"]), true),
+      (cond("request.path", Prefix, &["/api/v2/"]), false),
+      (cond("request.ip", InCidr, &["192.168.0.0/16", "10.0.0.0/8"]), true),
+      (cond("request.ip", InCidr, &["192.168.0.0/16"]), false),
+      (cond("request.header.X-Tenant", AnyOf, &["acme"]), true),
+      (cond("request.params.id", AnyOf, &["42"]), true),
+      (cond("claims.sub", Present, &[]), true),
+      (cond("claims.jti", Present, &[]), false),
+      (cond("claims.jti", Absent, &[]), true),
+      (cond("request.header.x-missing", Absent, &[]), true),
+      // a missing attribute resolves to no values
+      (cond("request.header.x-missing", NoneOf, &["acme"]), true),
+      (cond("request.header.x-missing", AnyOf, &["acme"]), false),
+    ];
+    for (cond, expected) in cases {
+      assert_eq!(holds(&cond, None), expected, "{:?}", cond);
+    }
+  }
+
+  #[test]
+  fn resolves_the_user_attributes() {
+    use ConditionOp::*;
+
+    let user = CachedUserData {
+      is_oauth: false,
+      roles: "customer, supplier".into(),
+      props: "verified,theme:light".into(),
+    };
+    let cases = [
+      (cond("user.roles", AnyOf, &["supplier"]), true),
+      (cond("user.props.verified", AnyOf, &["true"]), true),
+      (cond("user.props.theme", AnyOf, &["light"]), true),
+      (cond("user.props.theme", AnyOf, &["dark"]), false),
+      (cond("user.is_oauth", AnyOf, &["false"]), true),
+    ];
+    for (cond, expected) in cases {
+      assert_eq!(holds(&cond, Some(&user)), expected, "{:?}", cond);
+      // without a cached user, the user attributes resolve to no values
+      assert!(!holds(&cond, None), "{:?}", cond);
+    }
+  }
+
+  #[test]
+  fn matches_the_cidr_ranges() {
+    let cases = [
+      ("10.1.2.3", "10.0.0.0/8", true),
+      ("11.1.2.3", "10.0.0.0/8", false),
+      ("192.168.1.7", "192.168.1.0/24", true),
+      ("192.168.2.7", "192.168.1.0/24", false),
+      ("192.168.1.7", "192.168.1.7", true),
+      ("192.168.1.8", "192.168.1.7", false),
+      ("1.2.3.4", "0.0.0.0/0", true),
+      ("2001:db8::1", "2001:db8::/32", true),
+      ("2001:db9::1", "2001:db8::/32", false),
+      ("::1", "::1/128", true),
+      // the families never match each other
+      ("10.1.2.3", "::/0", false),
+      ("2001:db8::1", "0.0.0.0/0", false),
+      // malformed addresses and ranges never match
+      ("10.1.2.3", "10.0.0.0/33", false),
+      ("10.1.2.3", "10.0.0.0/x", false),
+      ("not-an-ip", "10.0.0.0/8", false),
+      ("", "0.0.0.0/0", false),
+    ];
+    for (ip, cidr, expected) in cases {
+      assert_eq!(ip_in_cidr(ip, cidr), expected, "{} in {}", ip, cidr);
+    }
+  }
+
+  #[test]
+  fn rejects_unknown_attributes() {
+    for attribute in ["request.pth", "user.role", "token.scope", "request.header.", "claims."] {
+      let policy = Policy {
+        name: "typo".into(),
+        routes: vec!["/api/**".into()],
+        when: vec![cond(attribute, ConditionOp::Present, &[])],
+        ..Default::default()
+      };
+      assert!(PolicySet::new(vec![policy]).is_err(), "{}", attribute);
+    }
+
+    let policy = Policy {
+      name: "known".into(),
+      routes: vec!["/api/**".into()],
+      when: vec![cond("request.header.x-tenant", ConditionOp::Present, &[])],
+      require: vec![cond("claims.tenant", ConditionOp::Present, &[])],
+      ..Default::default()
+    };
+    assert!(PolicySet::new(vec![policy]).is_ok());
+  }
+}
@@ -0,0 +1,15 @@
+use megacommerce_proto::CachedUserData;
+
+use crate::models::routes::RolesRequirement;
+
+use super::Controller;
+
+impl Controller {
+  /// Checks the route roles requirement against the roles cached for the token subject
+  pub(super) fn check_roles(required: &RolesRequirement, user: &CachedUserData) -> bool {
+    let roles: Vec<&str> =
+      user.roles.split(',').map(|r| r.trim()).filter(|r| !r.is_empty()).collect();
+
+    required.satisfied_by(&roles)
+  }
+}
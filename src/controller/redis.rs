@@ -1,7 +1,6 @@
 use std::io::{Error, ErrorKind};
 
 use deadpool_redis::{Connection, Pool};
-use megacommerce_proto::CachedTokenStatus;
 use megacommerce_shared::models::{
   errors::{BoxedErr, ErrorType, InternalError},
   r_lock::RLock,
@@ -9,34 +8,55 @@ use megacommerce_shared::models::{
 use tonic::async_trait;
 use tower::BoxError;
 
-use super::token::{check_token, get_token, mark_checked_ok, revoke_token, set_token};
+use crate::models::{config::TokensConfig, session::SessionRecord, token::TokenStatus};
+
+use super::session::{get_session, list_user_sessions, terminate_session, touch_session};
+use super::token::{
+  bind_device, check_token, get_token, get_user_not_before, lock_introspection, mark_checked_ok,
+  revoke_device_tokens, revoke_token, revoke_user_tokens, set_token, set_user_not_before,
+  unlock_introspection,
+};
 
 /// Represents Redis check results
 #[derive(Debug)]
 pub enum RedisCheck {
-  Allowed { status: Option<CachedTokenStatus> },
+  Allowed { status: Option<TokenStatus> },
   Revoked(String), // reason
 }
 
 #[async_trait]
 pub trait RedisClient: Send + Sync {
   async fn check_token(&self, token: &str) -> Result<RedisCheck, BoxedErr>;
-  async fn revoke_token(&self, token: &str) -> Result<(), BoxedErr>;
-  async fn mark_checked_ok(&self, token: &str) -> Result<(), BoxedErr>;
-  async fn get_token(&self, token: &str, path: &str)
-    -> Result<Option<CachedTokenStatus>, BoxedErr>;
-  async fn set_token(
+  async fn revoke_token(&self, token: &str, exp: Option<i64>) -> Result<bool, BoxedErr>;
+  async fn mark_checked_ok(
     &self,
-    jti: &str,
-    data: &CachedTokenStatus,
-    path: &str,
-  ) -> Result<(), BoxedErr>;
+    token: &str,
+    token_hash: &str,
+    user_id: &str,
+    scopes: &[String],
+    exp: Option<i64>,
+    device_id: Option<&str>,
+  ) -> Result<bool, BoxedErr>;
+  async fn bind_device(&self, token: &str, user_id: &str, device_id: &str) -> Result<(), BoxedErr>;
+  async fn revoke_user_tokens(&self, user_id: &str) -> Result<u32, BoxedErr>;
+  async fn revoke_device_tokens(&self, device_id: &str) -> Result<u32, BoxedErr>;
+  async fn get_user_not_before(&self, user_id: &str) -> Result<Option<i64>, BoxedErr>;
+  async fn set_user_not_before(&self, user_id: &str) -> Result<i64, BoxedErr>;
+  async fn get_token(&self, token: &str, path: &str) -> Result<Option<TokenStatus>, BoxedErr>;
+  async fn set_token(&self, jti: &str, data: &TokenStatus, path: &str) -> Result<(), BoxedErr>;
+  async fn lock_introspection(&self, jti: &str, ttl_ms: u64) -> Result<Option<String>, BoxedErr>;
+  async fn unlock_introspection(&self, jti: &str, owner: &str) -> Result<(), BoxedErr>;
+  async fn get_session(&self, jti: &str) -> Result<Option<SessionRecord>, BoxedErr>;
+  async fn touch_session(&self, jti: &str, ip: &str, user_agent: &str) -> Result<(), BoxedErr>;
+  async fn list_user_sessions(&self, user_id: &str) -> Result<Vec<SessionRecord>, BoxedErr>;
+  async fn terminate_session(&self, user_id: &str, jti: &str) -> Result<bool, BoxedErr>;
 }
 
 /// Concrete Redis client wrapper
 #[derive(Debug, Clone)]
 pub struct DefaultRedisClient {
   pub redis: RLock<Pool>,
+  pub tokens: TokensConfig,
 }
 
 impl DefaultRedisClient {
@@ -61,20 +81,11 @@ impl DefaultRedisClient {
 
 #[async_trait]
 impl RedisClient for DefaultRedisClient {
-  async fn get_token(
-    &self,
-    token: &str,
-    path: &str,
-  ) -> Result<Option<CachedTokenStatus>, BoxedErr> {
+  async fn get_token(&self, token: &str, path: &str) -> Result<Option<TokenStatus>, BoxedErr> {
     get_token(self, &token, &path).await
   }
 
-  async fn set_token(
-    &self,
-    jti: &str,
-    data: &CachedTokenStatus,
-    path: &str,
-  ) -> Result<(), BoxedErr> {
+  async fn set_token(&self, jti: &str, data: &TokenStatus, path: &str) -> Result<(), BoxedErr> {
     set_token(self, jti, data, path).await
   }
 
@@ -82,11 +93,63 @@ impl RedisClient for DefaultRedisClient {
     check_token(&self, &jti).await
   }
 
-  async fn revoke_token(&self, jti: &str) -> Result<(), BoxedErr> {
-    revoke_token(&self, &jti).await
+  async fn revoke_token(&self, jti: &str, exp: Option<i64>) -> Result<bool, BoxedErr> {
+    revoke_token(&self, &jti, exp).await
+  }
+
+  async fn mark_checked_ok(
+    &self,
+    jti: &str,
+    token_hash: &str,
+    user_id: &str,
+    scopes: &[String],
+    exp: Option<i64>,
+    device_id: Option<&str>,
+  ) -> Result<bool, BoxedErr> {
+    mark_checked_ok(&self, &jti, token_hash, user_id, scopes, exp, device_id).await
+  }
+
+  async fn bind_device(&self, jti: &str, user_id: &str, device_id: &str) -> Result<(), BoxedErr> {
+    bind_device(self, jti, user_id, device_id).await
+  }
+
+  async fn revoke_user_tokens(&self, user_id: &str) -> Result<u32, BoxedErr> {
+    revoke_user_tokens(self, user_id).await
+  }
+
+  async fn revoke_device_tokens(&self, device_id: &str) -> Result<u32, BoxedErr> {
+    revoke_device_tokens(self, device_id).await
+  }
+
+  async fn get_user_not_before(&self, user_id: &str) -> Result<Option<i64>, BoxedErr> {
+    get_user_not_before(self, user_id).await
+  }
+
+  async fn set_user_not_before(&self, user_id: &str) -> Result<i64, BoxedErr> {
+    set_user_not_before(self, user_id).await
+  }
+
+  async fn lock_introspection(&self, jti: &str, ttl_ms: u64) -> Result<Option<String>, BoxedErr> {
+    lock_introspection(self, jti, ttl_ms).await
+  }
+
+  async fn unlock_introspection(&self, jti: &str, owner: &str) -> Result<(), BoxedErr> {
+    unlock_introspection(self, jti, owner).await
+  }
+
+  async fn get_session(&self, jti: &str) -> Result<Option<SessionRecord>, BoxedErr> {
+    get_session(self, jti).await
+  }
+
+  async fn touch_session(&self, jti: &str, ip: &str, user_agent: &str) -> Result<(), BoxedErr> {
+    touch_session(self, jti, ip, user_agent).await
+  }
+
+  async fn list_user_sessions(&self, user_id: &str) -> Result<Vec<SessionRecord>, BoxedErr> {
+    list_user_sessions(self, user_id).await
   }
 
-  async fn mark_checked_ok(&self, jti: &str) -> Result<(), BoxedErr> {
-    mark_checked_ok(&self, &jti).await
+  async fn terminate_session(&self, user_id: &str, jti: &str) -> Result<bool, BoxedErr> {
+    terminate_session(self, user_id, jti).await
   }
 }
@@ -0,0 +1,80 @@
+use std::{
+  collections::HashSet,
+  sync::{Arc, Mutex},
+};
+
+use megacommerce_proto::JwtClaims;
+use tokio::{spawn, sync::Semaphore};
+
+use crate::utils::token::token_hash;
+
+use super::{hydra::HydraValidation, introspection::Introspector, metrics::Metrics};
+
+/// Revalidates the tokens nearing the end of their revalidation interval in the background,
+/// so the request that finds them is served from the cached status instead of waiting on
+/// hydra, at most `TokensConfig::refresh_concurrency` refreshes run at once, a token that
+/// can't get a slot is dropped, and validated inline once its status turns stale
+#[derive(Debug)]
+pub(super) struct Refresher {
+  introspector: Arc<Introspector>,
+  metrics: Arc<Metrics>,
+  permits: Arc<Semaphore>,
+  // the token hash of the scheduled refreshes
+  pending: Mutex<HashSet<String>>,
+}
+
+impl Refresher {
+  pub fn new(introspector: Arc<Introspector>, concurrency: usize, metrics: Arc<Metrics>) -> Self {
+    metrics.set_gauge("auth_refresh_ahead_inflight", &[], 0);
+    Self {
+      introspector,
+      metrics,
+      permits: Arc::new(Semaphore::new(concurrency.max(1))),
+      pending: Mutex::new(HashSet::new()),
+    }
+  }
+
+  /// Schedules a refresh of the token, unless one is already scheduled
+  pub fn schedule(self: &Arc<Self>, claims: &JwtClaims, token: &str) {
+    let hash = token_hash(token);
+    let permit = {
+      let mut pending = self.pending.lock().unwrap();
+      if pending.contains(&hash) {
+        return;
+      }
+
+      let Ok(permit) = self.permits.clone().try_acquire_owned() else {
+        self.metrics.incr("auth_refresh_ahead_total", &[("result", "dropped")]);
+        return;
+      };
+      pending.insert(hash.clone());
+      permit
+    };
+    self.set_inflight_gauge();
+
+    // a refreshed token already has a status, and keeps its device binding, it starts no
+    // session, the result of a refresh that raced a revocation is dropped, and the token
+    // is reported invalid
+    let (refresher, claims, token) = (self.clone(), claims.clone(), token.to_string());
+    spawn(async move {
+      let result = match refresher.introspector.introspect(&claims, &token, None).await {
+        Ok(HydraValidation::Valid { .. }) => "ok",
+        Ok(HydraValidation::Invalid(_)) => "invalid",
+        Err(err) => {
+          tracing::debug!(err = %err, jti = %claims.jti, "failed to refresh the token status");
+          "error"
+        }
+      };
+
+      drop(permit);
+      refresher.pending.lock().unwrap().remove(&hash);
+      refresher.set_inflight_gauge();
+      refresher.metrics.incr("auth_refresh_ahead_total", &[("result", result)]);
+    });
+  }
+
+  fn set_inflight_gauge(&self) {
+    let inflight = self.pending.lock().unwrap().len();
+    self.metrics.set_gauge("auth_refresh_ahead_inflight", &[], inflight as i64);
+  }
+}
@@ -4,7 +4,10 @@ use chrono::Utc;
 use megacommerce_proto::{
   config::core::v3::{header_value_option::HeaderAppendAction, HeaderValue, HeaderValueOption},
   google::{protobuf::BoolValue, rpc::Status},
-  service::auth::v3::{check_response::HttpResponse, CheckRequest, CheckResponse, OkHttpResponse},
+  r#type::v3::{HttpStatus, StatusCode},
+  service::auth::v3::{
+    check_response::HttpResponse, CheckRequest, CheckResponse, DeniedHttpResponse, OkHttpResponse,
+  },
   JwtClaims,
 };
 use megacommerce_shared::models::{
@@ -15,12 +18,13 @@ use megacommerce_shared::models::{
 };
 use tonic::{Code, Request, Response};
 
-use crate::utils::net::{extract_jwt_token_from_request, get_essential_http_headers};
+use crate::utils::net::{
+  client_ip, extract_device_id, extract_jwt_token_from_check_request, get_essential_http_headers,
+};
 
-use super::Controller;
+use super::{access::TokenState, Controller};
 
 impl Controller {
-  // TODO: handle getting the real ip
   pub async fn get_context(&self, req: &CheckRequest) -> Arc<Context> {
     let h = get_essential_http_headers(
       req,
@@ -37,7 +41,7 @@ impl Controller {
 
     Arc::new(Context {
       session: Session::default(),
-      ip_address: h.x_forwarded_for.clone(),
+      ip_address: client_ip(req, &h.x_forwarded_for, self.service_config.network.xff_trusted_hops),
       x_forwarded_for: h.x_forwarded_for,
       request_id: h.x_request_id,
       path,
@@ -51,8 +55,9 @@ impl Controller {
     ctx: &Arc<Context>,
     req: &Request<CheckRequest>,
     claims: Option<JwtClaims>,
+    last_active: Option<i64>,
   ) -> Response<CheckResponse> {
-    let headers = self.prepare_headers(ctx, req, claims).await;
+    let headers = self.prepare_headers(ctx, req, claims, last_active).await;
     if headers.is_err() {
       self.report_internal_error(headers.unwrap_err());
       return Response::new(CheckResponse {
@@ -80,9 +85,11 @@ impl Controller {
     ctx: &Arc<Context>,
     req: &Request<CheckRequest>,
     claims: Option<JwtClaims>,
+    last_active: Option<i64>,
   ) -> Result<Vec<HeaderValueOption>, BoxedErr> {
     let mut headers: Vec<HeaderValueOption> = vec![];
-    let device_id = "dump device id";
+    let devices = &self.service_config.devices;
+    let device_id = extract_device_id(req.get_ref(), &devices.header, &devices.cookie);
 
     let header = |header: Header, value: String| {
       HeaderValueOption {
@@ -97,41 +104,47 @@ impl Controller {
       }
     };
 
-    if claims.is_some() {
-      let c = claims.unwrap().clone();
-      if c.jti.clone().is_empty() && c.sub.clone().is_empty() {
-        let token = extract_jwt_token_from_request(req).unwrap_or_default();
-        let user_id = c.sub.clone();
-        let auth_data =
-          self.get_or_insert_auth_cached_user_data(ctx.clone(), &user_id).await.map_err(|err| {
-            let msg =
-              "failed to get/insert uesr data to be fowarded to downstream services as metadata";
-            InternalError {
-              err,
-              err_type: ErrorType::Internal,
-              msg: msg.into(),
-              temp: true,
-              path: "auth.controller.prepare_headers".into(),
-            }
-          })?;
-
-        headers.push(header(Header::SessionId, c.jti));
-        headers.push(header(Header::Token, token));
-        headers.push(header(
-          Header::CreatedAt,
-          c.iat.and_then(|t| t.seconds.to_string().into()).unwrap_or_default(),
-        ));
-        headers.push(header(
-          Header::ExpiresAt,
-          c.exp.and_then(|t| t.seconds.to_string().into()).unwrap_or_default(),
-        ));
-        headers.push(header(Header::LastActivityAt, Utc::now().timestamp().to_string()));
-        headers.push(header(Header::UserId, c.sub));
-        headers.push(header(Header::DeviceId, device_id.to_string()));
-        headers.push(header(Header::Roles, auth_data.roles));
-        headers.push(header(Header::IsOauth, auth_data.is_oauth.to_string()));
-        headers.push(header(Header::Props, auth_data.props));
-      }
+    // the identity headers are only sent for a token with a known subject, of an existing
+    // user, a deleted user's token is only allowed where no role or policy needs the user
+    let identity = match claims.filter(|c| !c.sub.is_empty()) {
+      Some(c) => self
+        .get_or_insert_auth_cached_user_data(ctx.clone(), &c.sub)
+        .await
+        .map_err(|err| {
+          let msg =
+            "failed to get/insert uesr data to be fowarded to downstream services as metadata";
+          InternalError {
+            err,
+            err_type: ErrorType::Internal,
+            msg: msg.into(),
+            temp: true,
+            path: "auth.controller.prepare_headers".into(),
+          }
+        })?
+        .map(|auth_data| (c, auth_data)),
+      None => None,
+    };
+
+    if let Some((c, auth_data)) = identity {
+      let token = extract_jwt_token_from_check_request(req).unwrap_or_default();
+      headers.push(header(Header::SessionId, c.jti));
+      headers.push(header(Header::Token, token));
+      headers.push(header(
+        Header::CreatedAt,
+        c.iat.and_then(|t| t.seconds.to_string().into()).unwrap_or_default(),
+      ));
+      headers.push(header(
+        Header::ExpiresAt,
+        c.exp.and_then(|t| t.seconds.to_string().into()).unwrap_or_default(),
+      ));
+      // the previous use of the session, this one for a new or an untracked session
+      let last_active = last_active.unwrap_or_else(|| Utc::now().timestamp());
+      headers.push(header(Header::LastActivityAt, last_active.to_string()));
+      headers.push(header(Header::UserId, c.sub));
+      headers.push(header(Header::DeviceId, device_id.unwrap_or_default()));
+      headers.push(header(Header::Roles, auth_data.data.roles));
+      headers.push(header(Header::IsOauth, auth_data.data.is_oauth.to_string()));
+      headers.push(header(Header::Props, auth_data.data.props));
     }
 
     headers.push(header(Header::XRequestId, ctx.request_id.clone()));
@@ -143,6 +156,32 @@ impl Controller {
     Ok(headers)
   }
 
+  /// Flags an allowed response with the degraded and device mismatch state of its token
+  pub(super) fn flag_token_state(res: &mut Response<CheckResponse>, state: &TokenState) {
+    if let TokenState::Valid { degraded, device_mismatch, .. } = state {
+      if *degraded {
+        Self::flag(res, "x-auth-degraded");
+      }
+      if *device_mismatch {
+        Self::flag(res, "x-auth-device-mismatch");
+      }
+    }
+  }
+
+  /// Flags an allowed response with a `<key>: true` header, to the upstream service and
+  /// to the client, E,g x-auth-degraded for a token allowed while hydra is unreachable
+  pub fn flag(res: &mut Response<CheckResponse>, key: &str) {
+    if let Some(HttpResponse::OkResponse(ok)) = res.get_mut().http_response.as_mut() {
+      let header = HeaderValueOption {
+        append_action: HeaderAppendAction::OverwriteIfExistsOrAdd.into(),
+        header: Some(HeaderValue { key: key.into(), value: "true".into(), raw_value: Vec::new() }),
+        ..Default::default()
+      };
+      ok.headers.push(header.clone());
+      ok.response_headers_to_add.push(header);
+    }
+  }
+
   pub fn not_found_msg(lang: &str) -> String {
     return tr::<String>(lang, "error.not_found", None)
       .unwrap_or("The requested path is not provided!".into());
@@ -153,6 +192,11 @@ impl Controller {
       .unwrap_or("Sorry, the authentication payload is invalid, please login first".into());
   }
 
+  pub fn permission_denied_msg(lang: &str) -> String {
+    return tr::<String>(lang, "error.permission_denied", None)
+      .unwrap_or("Sorry, you don't have the permission to access this resource".into());
+  }
+
   pub fn int_err_msg(lang: &str) -> String {
     return tr::<String>(lang, "error.internal", None).unwrap_or(
       "Sorry, Unexpected internal server error. Our team has been notified. Please try again"
@@ -163,6 +207,7 @@ impl Controller {
 
 pub trait CheckResponseExt {
   fn denied(msg: &str) -> Self;
+  fn not_found(msg: &str) -> Self;
 }
 
 impl CheckResponseExt for CheckResponse {
@@ -176,4 +221,20 @@ impl CheckResponseExt for CheckResponse {
       ..Default::default()
     }
   }
+
+  fn not_found(msg: &str) -> Self {
+    Self {
+      status: Some(Status {
+        code: Code::NotFound as i32,
+        message: msg.to_string(),
+        details: vec![],
+      }),
+      http_response: Some(HttpResponse::DeniedResponse(DeniedHttpResponse {
+        status: Some(HttpStatus { code: StatusCode::NotFound as i32 }),
+        body: msg.to_string(),
+        ..Default::default()
+      })),
+      ..Default::default()
+    }
+  }
 }
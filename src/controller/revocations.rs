@@ -0,0 +1,212 @@
+use std::{
+  collections::HashMap,
+  sync::{Arc, RwLock},
+  time::Duration,
+};
+
+use deadpool_redis::redis::{AsyncCommands, Client};
+use futures_util::StreamExt;
+use megacommerce_shared::{
+  models::errors::{BoxedErr, ErrorType, InternalError},
+  utils::time::time_get_seconds,
+};
+use tokio::{select, spawn, time::interval};
+
+use crate::models::{
+  redis::auth_revoked_tokens_key,
+  token::{RevocationEvent, UserEvent},
+};
+
+use super::{
+  metrics::Metrics,
+  redis::{DefaultRedisClient, RedisClient},
+  user_cache::invalidate_auth_cached_user_data,
+};
+
+/// The revoked token ids known to this instance, so a revoked token is denied without
+/// a redis round-trip, it's filled from the revocations channel, and backfilled from the
+/// `auth_revoked_tokens_key` set on startup and after every reconnect
+#[derive(Debug, Default)]
+pub(super) struct RevocationCache {
+  // jti => unix seconds until the entry is kept, same as the redis token status
+  revoked: RwLock<HashMap<String, i64>>,
+}
+
+impl RevocationCache {
+  pub fn contains(&self, jti: &str) -> bool {
+    let now = time_get_seconds() as i64;
+    self.revoked.read().unwrap().get(jti).is_some_and(|until| *until > now)
+  }
+
+  pub fn insert(&self, jti: String, until: i64) {
+    self.revoked.write().unwrap().insert(jti, until);
+  }
+
+  /// Removes the expired entries, returns the number of the remaining ones
+  pub fn prune(&self) -> usize {
+    let now = time_get_seconds() as i64;
+    let mut revoked = self.revoked.write().unwrap();
+    revoked.retain(|_, until| *until > now);
+    revoked.len()
+  }
+}
+
+/// Subscribes to the revocations channel in the background, reconnecting on failures
+pub(super) fn watch_revocations(
+  redis_url: String,
+  redis: DefaultRedisClient,
+  cache: Arc<RevocationCache>,
+  metrics: Arc<Metrics>,
+) {
+  spawn(async move {
+    loop {
+      if let Err(err) = sync_revocations(&redis_url, &redis, &cache, &metrics).await {
+        tracing::error!(err = %err, "the revocations subscription failed, retrying");
+      }
+      tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+  });
+}
+
+async fn sync_revocations(
+  redis_url: &str,
+  redis: &DefaultRedisClient,
+  cache: &RevocationCache,
+  metrics: &Metrics,
+) -> Result<(), BoxedErr> {
+  let path = "auth.controller.sync_revocations";
+  let ie = |err: BoxedErr, msg: &str| {
+    InternalError::new(path.into(), err, ErrorType::Internal, true, msg.into())
+  };
+
+  let client =
+    Client::open(redis_url).map_err(|err| ie(Box::new(err), "failed to create a redis client"))?;
+  let mut pubsub = client
+    .get_async_pubsub()
+    .await
+    .map_err(|err| ie(Box::new(err), "failed to get a redis pubsub connection"))?;
+  pubsub
+    .subscribe(&redis.tokens.revocations_channel)
+    .await
+    .map_err(|err| ie(Box::new(err), "failed to subscribe to the revocations channel"))?;
+
+  // subscribed first, so a revocation published while backfilling isn't missed
+  let now = time_get_seconds() as i64;
+  let mut con = redis.get_conn(path).await?;
+  let revoked: Vec<(String, i64)> = con
+    .zrangebyscore_withscores(auth_revoked_tokens_key(), now, "+inf")
+    .await
+    .map_err(|err| ie(Box::new(err), "failed to backfill the revoked tokens"))?;
+  drop(con);
+
+  for (jti, until) in revoked {
+    cache.insert(jti, until);
+  }
+
+  let mut messages = pubsub.on_message();
+  let mut prune = interval(Duration::from_secs(60));
+  loop {
+    select! {
+      msg = messages.next() => {
+        let Some(msg) = msg else {
+          return Ok(());
+        };
+        match serde_json::from_slice::<RevocationEvent>(msg.get_payload_bytes()) {
+          Ok(event) => cache.insert(event.jti, event.until),
+          Err(err) => tracing::warn!(err = %err, "malformed revocation event"),
+        }
+      }
+      _ = prune.tick() => {
+        let len = cache.prune();
+        metrics.set_gauge("auth_local_revocations", &[], len as i64);
+      }
+    }
+  }
+}
+
+/// Subscribes to the user events channel in the background, reconnecting on failures,
+/// every event drops the cached user auth data (E,g a role change), and the
+/// `TokensConfig::not_before_events` set the user not-before epoch
+pub(super) fn watch_user_events(
+  redis_url: String,
+  redis: DefaultRedisClient,
+  metrics: Arc<Metrics>,
+) {
+  spawn(async move {
+    loop {
+      if let Err(err) = sync_user_events(&redis_url, &redis, &metrics).await {
+        tracing::error!(err = %err, "the user events subscription failed, retrying");
+      }
+      tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+  });
+}
+
+async fn sync_user_events(
+  redis_url: &str,
+  redis: &DefaultRedisClient,
+  metrics: &Metrics,
+) -> Result<(), BoxedErr> {
+  let path = "auth.controller.sync_user_events";
+  let ie = |err: BoxedErr, msg: &str| {
+    InternalError::new(path.into(), err, ErrorType::Internal, true, msg.into())
+  };
+
+  let client =
+    Client::open(redis_url).map_err(|err| ie(Box::new(err), "failed to create a redis client"))?;
+  let mut pubsub = client
+    .get_async_pubsub()
+    .await
+    .map_err(|err| ie(Box::new(err), "failed to get a redis pubsub connection"))?;
+  pubsub
+    .subscribe(&redis.tokens.user_events_channel)
+    .await
+    .map_err(|err| ie(Box::new(err), "failed to subscribe to the user events channel"))?;
+
+  let mut messages = pubsub.on_message();
+  while let Some(msg) = messages.next().await {
+    let event = match serde_json::from_slice::<UserEvent>(msg.get_payload_bytes()) {
+      Ok(event) => event,
+      Err(err) => {
+        tracing::warn!(err = %err, "malformed user event");
+        continue;
+      }
+    };
+    if event.user_id.is_empty() {
+      continue;
+    }
+
+    if let Err(err) = invalidate_auth_cached_user_data(redis, &event.user_id).await {
+      tracing::error!(
+        err = %err,
+        user_id = %event.user_id,
+        event = %event.event_type,
+        "failed to drop the cached user data of a user event"
+      );
+    }
+    if !redis.tokens.not_before_events.contains(&event.event_type) {
+      continue;
+    }
+
+    // a failed write isn't retried, the users service can still call SetUserNotBefore
+    match redis.set_user_not_before(&event.user_id).await {
+      Ok(not_before) => {
+        metrics.incr("auth_user_not_before_events_total", &[("event", &event.event_type)]);
+        tracing::info!(
+          user_id = %event.user_id,
+          event = %event.event_type,
+          not_before,
+          "user tokens issued before the epoch revoked by a user event"
+        );
+      }
+      Err(err) => tracing::error!(
+        err = %err,
+        user_id = %event.user_id,
+        event = %event.event_type,
+        "failed to set the user not-before epoch of a user event"
+      ),
+    }
+  }
+
+  Ok(())
+}
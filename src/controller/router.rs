@@ -1,16 +1,26 @@
-use megacommerce_proto::service::auth::v3::{
-  authorization_server::Authorization, CheckRequest, CheckResponse,
+use std::collections::HashMap;
+
+use megacommerce_proto::{
+  service::auth::v3::{authorization_server::Authorization, CheckRequest, CheckResponse},
+  JwtClaims,
 };
-use megacommerce_shared::utils::time::time_get_seconds;
 use tonic::{Code, Request, Response, Status};
 
-use crate::utils::net::extract_jwt_claims_from_request;
+use crate::{
+  models::{
+    config::UnmatchedRoutePolicy,
+    routes::{RouteEntry, RouteRule},
+  },
+  utils::{
+    matcher::normalize_path,
+    net::{extract_device_id, extract_jwt_token_from_check_request},
+  },
+};
 
 use super::{
-  hydra::{HydraClient, HydraValidation},
-  redis::{RedisCheck, RedisClient},
+  access::{Decision, TokenState},
   response::CheckResponseExt,
-  routes::ROUTES,
+  routes::RouteMatch,
   Controller,
 };
 
@@ -23,66 +33,112 @@ impl Authorization for Controller {
     let req = request.get_ref();
     let lang = ctx.accept_language();
 
-    let path = req
+    let (method, path) = req
       .attributes
       .as_ref()
       .and_then(|a| a.request.as_ref())
       .and_then(|r| r.http.as_ref())
-      .map(|h| h.path.clone())
+      .map(|h| (h.method.clone(), h.path.clone()))
       .ok_or_else(|| Status::new(Code::NotFound, Self::not_found_msg(lang)))?;
 
-    let protected = match ROUTES.get(&path) {
-      Some(res) => *res,
-      None => return Err(Status::new(Code::NotFound, Self::not_found_msg(lang))),
-    };
-
-    if !protected {
-      return Ok(self.response_ok(&ctx, &request, None).await);
-    }
-
-    let claims = extract_jwt_claims_from_request(&request);
-    let token = claims.jti.clone();
-
-    // the token id must be present, for a protected route
-    if token.is_empty() {
-      return Ok(Response::new(CheckResponse::denied(&Self::invalid_token_msg(lang))));
-    }
+    let routes = self.routes.read().await.clone();
+    let protected_rule = RouteRule { protected: true, ..Default::default() };
+    let fallback = RouteEntry { path: path.clone(), rule: protected_rule, ..Default::default() };
+    let route = match routes.find(&method, &path) {
+      Some(route) => route,
+      None => {
+        let policy = self.service_config.routes.unmatched;
+        tracing::warn!(
+          method = %method,
+          path = %path,
+          policy = %policy,
+          request_id = %ctx.request_id,
+          "no route entry matches the request"
+        );
+        let route_path = normalize_path(&path);
+        self.metrics.incr(
+          "auth_unmatched_routes_total",
+          &[
+            ("method", method.as_str()),
+            ("path", route_path.as_str()),
+            ("policy", policy.to_string().as_str()),
+          ],
+        );
 
-    match self.redis.check_token(&token).await {
-      Ok(RedisCheck::Revoked(_)) => {
-        return Ok(Response::new(CheckResponse::denied(&Self::invalid_token_msg(lang))));
+        match policy {
+          UnmatchedRoutePolicy::DenyForbidden => {
+            return Ok(Response::new(CheckResponse::denied(&Self::permission_denied_msg(lang))));
+          }
+          UnmatchedRoutePolicy::DenyNotFound => {
+            return Ok(Response::new(CheckResponse::not_found(&Self::not_found_msg(lang))));
+          }
+          UnmatchedRoutePolicy::Protected => {
+            RouteMatch { entry: &fallback, params: HashMap::new() }
+          }
+        }
       }
-      Ok(RedisCheck::Allowed { status }) => {
-        let now = time_get_seconds();
-        let needs_hydra = match status {
-          Some(st) => now as i64 - st.last_checked > 300,
-          None => true,
-        };
+    };
 
-        if needs_hydra {
-          // TODO: handle mark_checked_ok, revoke_token errors
-          match self.hydra.validate_token(&token).await {
-            Ok(HydraValidation::Valid { sub: _, exp: _ }) => {
-              self.redis.mark_checked_ok(&token).await.ok();
-              return Ok(self.response_ok(&ctx, &request, Some(claims)).await);
-            }
-            Ok(HydraValidation::Invalid(_)) => {
-              self.redis.revoke_token(&token).await.ok();
-              return Ok(Response::new(CheckResponse::denied(&Self::invalid_token_msg(lang))));
-            }
-            Err(err) => {
-              self.report_internal_error(err);
-              return Err(Status::internal(Self::int_err_msg(lang)));
-            }
+    // a public route skips the token validation, its request-only policies still apply
+    let protected = route.entry.rule.protected;
+    let (claims, state) = if route.entry.needs_token() {
+      let raw_token = extract_jwt_token_from_check_request(&request);
+      let claims = self.request_claims(req, raw_token.as_deref()).await;
+      let devices = &self.service_config.devices;
+      let device_id = extract_device_id(req, &devices.header, &devices.cookie);
+      let state = self
+        .validate_token_state(
+          route.entry,
+          &claims,
+          raw_token.as_deref(),
+          device_id.as_deref(),
+          &ctx,
+        )
+        .await;
+      match state {
+        Ok(state) => (claims, state),
+        Err(err) => {
+          self.report_internal_error(err);
+          if !protected {
+            return Ok(self.response_ok(&ctx, &request, None, None).await); // only the shadow needs it
           }
-        } else {
-          return Ok(self.response_ok(&ctx, &request, Some(claims)).await); // Cached as valid
+          return Err(Status::internal(Self::int_err_msg(lang)));
         }
       }
+    } else {
+      (JwtClaims::default(), TokenState::Missing)
+    };
+
+    match self.authorize(&ctx, req, route.entry, &route.params, &claims, &state).await {
+      Ok(Decision::Allow) => {}
+      Ok(Decision::Deny { rule, reason }) => {
+        tracing::info!(
+          rule = %rule,
+          reason = %reason,
+          method = %method,
+          path = %path,
+          request_id = %ctx.request_id,
+          "request denied"
+        );
+        let msg = match rule.as_str() {
+          "token" => Self::invalid_token_msg(lang),
+          _ => Self::permission_denied_msg(lang),
+        };
+        return Ok(Response::new(CheckResponse::denied(&msg)));
+      }
       Err(err) => {
         self.report_internal_error(err);
         return Err(Status::internal(Self::int_err_msg(lang)));
       }
     }
+
+    let claims = if protected { Some(claims) } else { None };
+    let last_active = match state {
+      TokenState::Valid { last_active, .. } => last_active,
+      _ => None,
+    };
+    let mut res = self.response_ok(&ctx, &request, claims, last_active).await;
+    Self::flag_token_state(&mut res, &state);
+    Ok(res)
   }
 }
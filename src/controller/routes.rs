@@ -1,6 +1,327 @@
-use phf::{phf_map, Map};
+use std::{
+  collections::HashMap,
+  fs,
+  io::{Error, ErrorKind},
+  path::Path,
+  sync::Arc,
+  time::Duration,
+};
+
+use megacommerce_shared::models::{
+  context::Context,
+  errors::{BoxedErr, ErrorType, InternalError},
+  r_lock::RLock,
+};
+use tokio::{spawn, sync::RwLock, time::interval};
 
-pub(super) static ROUTES: Map<&'static str, bool> = phf_map! {
-  "/users.v1.UsersService/CreateSupplier" =>  false,
-  "/users.v1.UsersService/Login" =>  false,
+use crate::{
+  models::{
+    config::RoutesConfig,
+    routes::{RouteEntry, RouteTableRecord},
+  },
+  store::database::AuthStore,
+  utils::matcher::RoutePattern,
 };
+
+/// The route protection table, it's swapped as a whole on reload, so in-flight
+/// checks keep using the snapshot they started with
+#[derive(Debug, Default)]
+pub(super) struct RouteTable {
+  pub version: String,
+  // the entries restricted to fewer methods come first for the same path
+  exact: HashMap<String, Vec<RouteEntry>>,
+  // sorted by precedence, the most specific pattern comes first
+  patterns: Vec<(RoutePattern, RouteEntry)>,
+}
+
+/// The route entry that matched a request, with the captured path params
+#[derive(Debug)]
+pub(super) struct RouteMatch<'a> {
+  pub entry: &'a RouteEntry,
+  pub params: HashMap<String, String>,
+}
+
+impl RouteTable {
+  pub fn new(version: String, entries: Vec<RouteEntry>) -> Result<Self, Error> {
+    // a later entry overrides an earlier one with the same pattern and methods in place,
+    // so the entries keep their source order
+    let mut unique: Vec<RouteEntry> = vec![];
+    for mut entry in entries {
+      entry.methods = entry.methods.iter().map(|m| m.to_uppercase()).collect();
+      entry.methods.sort();
+      entry.methods.dedup();
+      match unique.iter_mut().find(|e| e.path == entry.path && e.methods == entry.methods) {
+        Some(existing) => *existing = entry,
+        None => unique.push(entry),
+      }
+    }
+
+    let mut exact: HashMap<String, Vec<RouteEntry>> = HashMap::new();
+    let mut patterns = vec![];
+    for entry in unique {
+      if !entry.rule.is_valid() || entry.shadow.as_ref().is_some_and(|s| !s.is_valid()) {
+        let msg =
+          format!("the route {} requires roles or scopes, but it's not protected", entry.path);
+        return Err(Error::new(ErrorKind::InvalidInput, msg));
+      }
+
+      match RoutePattern::parse(&entry.path)? {
+        RoutePattern::Exact(_) => exact.entry(entry.path.clone()).or_default().push(entry),
+        pattern => patterns.push((pattern, entry)),
+      }
+    }
+
+    // the sorts are stable, so the overlapping entries with the same precedence
+    // (E,g [GET, POST] and [GET, PUT]) are matched in their source order
+    for entries in exact.values_mut() {
+      entries.sort_by_key(method_precedence);
+    }
+
+    patterns.sort_by(|(a, ae), (b, be)| {
+      b.specificity()
+        .cmp(&a.specificity())
+        .then_with(|| method_precedence(ae).cmp(&method_precedence(be)))
+    });
+
+    Ok(Self { version, exact, patterns })
+  }
+
+  /// Finds the most specific entry matching the method and the path, the query string is ignored
+  pub fn find(&self, method: &str, path: &str) -> Option<RouteMatch<'_>> {
+    let path = path.split('?').next().unwrap_or_default();
+    let allows = |e: &RouteEntry| {
+      e.methods.is_empty() || e.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+    };
+
+    let exact = self.exact.get(path).and_then(|entries| entries.iter().find(|e| allows(e)));
+    if let Some(entry) = exact {
+      return Some(RouteMatch { entry, params: HashMap::new() });
+    }
+
+    self
+      .patterns
+      .iter()
+      .filter(|(_, entry)| allows(entry))
+      .find_map(|(pattern, entry)| pattern.matches(path).map(|params| RouteMatch { entry, params }))
+  }
+}
+
+/// The entries restricted to fewer methods come first, the ones allowing any method come last
+fn method_precedence(entry: &RouteEntry) -> (bool, usize) {
+  (entry.methods.is_empty(), entry.methods.len())
+}
+
+/// Shared handle to the current route table
+pub(super) type SharedRouteTable = Arc<RwLock<Arc<RouteTable>>>;
+
+/// Loads the route table from the configured sources, see `RoutesConfig`
+pub(super) async fn load_route_table(
+  cfg: &RoutesConfig,
+  store: &RLock<dyn AuthStore + Send + Sync>,
+) -> Result<RouteTable, BoxedErr> {
+  let path = "auth.controller.load_route_table";
+  let ie = |err: BoxedErr, msg: &str| {
+    InternalError::new(path.into(), err, ErrorType::ConfigError, false, msg.into())
+  };
+
+  let mut versions = vec![];
+  let mut entries = cfg.entries.clone();
+  if !cfg.version.is_empty() {
+    versions.push(cfg.version.clone());
+  }
+
+  if let Some(file) = &cfg.file {
+    let content = fs::read_to_string(file)
+      .map_err(|err| ie(Box::new(err), "failed to read the route table file"))?;
+
+    let is_json = Path::new(file).extension().is_some_and(|ext| ext == "json");
+    let record: RouteTableRecord = if is_json {
+      serde_json::from_str(&content)
+        .map_err(|err| ie(Box::new(err), "failed to parse the route table file"))?
+    } else {
+      serde_yaml::from_str(&content)
+        .map_err(|err| ie(Box::new(err), "failed to parse the route table file"))?
+    };
+
+    versions.push(format!("file:{}", record.version));
+    entries.extend(record.routes);
+  }
+
+  if cfg.database {
+    let ctx = Arc::new(Context::default());
+    let record = store
+      .get()
+      .await
+      .routes_get_latest(ctx)
+      .await
+      .map_err(|err| ie(Box::new(err), "failed to get the route table from database"))?;
+
+    if let Some(record) = record {
+      versions.push(format!("db:{}", record.version));
+      entries.extend(record.routes);
+    }
+  }
+
+  if entries.is_empty() {
+    let err = Error::new(ErrorKind::InvalidData, "the route table is empty");
+    return Err(Box::new(ie(Box::new(err), "no routes are configured")));
+  }
+
+  RouteTable::new(versions.join("+"), entries)
+    .map_err(|err| Box::new(ie(Box::new(err), "failed to build the route table")) as BoxedErr)
+}
+
+/// Reloads the route table every `reload_interval_secs`, a failed reload keeps the current table
+pub(super) fn watch_route_table(
+  cfg: RoutesConfig,
+  store: RLock<dyn AuthStore + Send + Sync>,
+  routes: SharedRouteTable,
+) {
+  if cfg.reload_interval_secs == 0 {
+    return;
+  }
+
+  spawn(async move {
+    let mut ticker = interval(Duration::from_secs(cfg.reload_interval_secs));
+    ticker.tick().await; // the first tick completes immediately
+
+    loop {
+      ticker.tick().await;
+      match load_route_table(&cfg, &store).await {
+        Ok(table) => {
+          let current = routes.read().await.version.clone();
+          if current != table.version {
+            tracing::info!(from = %current, to = %table.version, "swapping the route table");
+          }
+          *routes.write().await = Arc::new(table);
+        }
+        Err(err) => tracing::error!("failed to reload the route table: {}", err),
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(path: &str, methods: &[&str]) -> RouteEntry {
+    let methods = methods.iter().map(|m| m.to_string()).collect();
+    RouteEntry { path: path.into(), methods, ..Default::default() }
+  }
+
+  /// The path and methods of the entry matching the request, joined for the assertions
+  fn found(table: &RouteTable, method: &str, path: &str) -> Option<String> {
+    let entry = table.find(method, path)?.entry;
+    Some(format!("{} {}", entry.methods.join(","), entry.path).trim().to_string())
+  }
+
+  #[test]
+  fn the_most_specific_entry_wins() {
+    let table = RouteTable::new(
+      "v1".into(),
+      vec![
+        entry("/api/v1/**", &[]),
+        entry("/api/v1/products/**", &[]),
+        entry("/api/v1/products/*", &[]),
+        entry("/api/v1/products/{id}/reviews", &[]),
+        entry("/api/v1/products/featured", &[]),
+        entry("/api/v1/products/x*", &[]),
+      ],
+    )
+    .unwrap();
+
+    let cases = [
+      ("/api/v1/products/featured", Some("/api/v1/products/featured")),
+      ("/api/v1/products/featured?page=2", Some("/api/v1/products/featured")),
+      ("/api/v1/products/xl", Some("/api/v1/products/x*")),
+      ("/api/v1/products/42", Some("/api/v1/products/*")),
+      ("/api/v1/products/42/reviews", Some("/api/v1/products/{id}/reviews")),
+      ("/api/v1/products/42/reviews/", Some("/api/v1/products/**")),
+      ("/api/v1/products/", Some("/api/v1/products/*")),
+      ("/api/v1/products", Some("/api/v1/products/**")),
+      ("/api/v1/orders/42", Some("/api/v1/**")),
+      ("/api/v2/orders", None),
+    ];
+    for (path, expected) in cases {
+      assert_eq!(found(&table, "GET", path).as_deref(), expected, "{}", path);
+    }
+  }
+
+  #[test]
+  fn a_method_specific_entry_wins_over_an_any_method_one() {
+    let table = RouteTable::new(
+      "v1".into(),
+      vec![
+        entry("/api/v1/products", &[]),
+        entry("/api/v1/products", &["post", "PUT"]),
+        entry("/api/v1/products/{id}", &[]),
+        entry("/api/v1/products/{id}", &["DELETE"]),
+      ],
+    )
+    .unwrap();
+
+    let cases = [
+      ("GET", "/api/v1/products", "/api/v1/products"),
+      ("POST", "/api/v1/products", "POST,PUT /api/v1/products"),
+      ("put", "/api/v1/products", "POST,PUT /api/v1/products"),
+      ("GET", "/api/v1/products/42", "/api/v1/products/{id}"),
+      ("DELETE", "/api/v1/products/42", "DELETE /api/v1/products/{id}"),
+    ];
+    for (method, path, expected) in cases {
+      assert_eq!(found(&table, method, path).as_deref(), Some(expected), "{} {}", method, path);
+    }
+  }
+
+  #[test]
+  fn an_entry_restricted_to_other_methods_doesnt_match() {
+    let table = RouteTable::new("v1".into(), vec![entry("/api/v1/products", &["POST"])]).unwrap();
+    assert!(table.find("GET", "/api/v1/products").is_none());
+  }
+
+  #[test]
+  fn overlapping_method_sets_match_deterministically() {
+    // fewer methods first, then the source order, on exact paths and on patterns
+    for path in ["/api/v1/products", "/api/v1/products/{id}"] {
+      let table = RouteTable::new(
+        "v1".into(),
+        vec![
+          entry(path, &["GET", "POST", "PUT"]),
+          entry(path, &["GET", "POST"]),
+          entry(path, &["GET", "PUT"]),
+        ],
+      )
+      .unwrap();
+
+      let request = path.replace("{id}", "42");
+      let cases = [("GET", "GET,POST"), ("POST", "GET,POST"), ("PUT", "GET,PUT")];
+      for (method, expected) in cases {
+        let expected = format!("{} {}", expected, path);
+        let found = found(&table, method, &request);
+        assert_eq!(found.as_deref(), Some(expected.as_str()), "{} {}", method, request);
+      }
+    }
+  }
+
+  #[test]
+  fn a_later_duplicate_overrides_in_place() {
+    let protected = |path: &str, methods: &[&str]| {
+      let mut e = entry(path, methods);
+      e.rule.protected = true;
+      e
+    };
+    let table = RouteTable::new(
+      "v1".into(),
+      vec![
+        entry("/api/v1/products", &["GET", "POST"]),
+        entry("/api/v1/products", &["GET", "PUT"]),
+        protected("/api/v1/products", &["post", "get"]),
+      ],
+    )
+    .unwrap();
+
+    let route = table.find("GET", "/api/v1/products").unwrap();
+    assert_eq!(route.entry.methods, ["GET", "POST"]);
+    assert!(route.entry.rule.protected);
+  }
+}
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+
+use deadpool_redis::redis::{cmd, pipe, AsyncCommands, Pipeline};
+use megacommerce_shared::{
+  models::{
+    errors::{BoxedErr, ErrorType, InternalError},
+    redis::auth_token_status_key,
+  },
+  utils::time::time_get_seconds,
+};
+
+use crate::models::{
+  redis::{auth_session_key, auth_user_tokens_key},
+  session::SessionRecord,
+  token::TokenStatus,
+};
+
+use super::redis::{DefaultRedisClient, RedisClient};
+
+/// Adds the validation of a token to its session: the first use, the bound device
+/// and the expiry, the session expires with the token status
+pub(super) fn record_session(p: &mut Pipeline, jti: &str, data: &TokenStatus, ttl: u64) {
+  let key = auth_session_key(jti);
+  p.hset_nx(&key, "first_seen", time_get_seconds() as i64).ignore();
+  if !data.status.dev_id.is_empty() {
+    p.hset(&key, "device_id", &data.status.dev_id).ignore();
+  }
+  if let Some(exp) = data.exp {
+    p.hset(&key, "exp", exp).ignore();
+  }
+  p.expire(&key, ttl as i64).ignore();
+}
+
+pub(super) async fn get_session(
+  r: &DefaultRedisClient,
+  jti: &str,
+) -> Result<Option<SessionRecord>, BoxedErr> {
+  let path = "auth.controller.get_session";
+  let mut con = r.get_conn(path).await?;
+  let fields: HashMap<String, String> =
+    con.hgetall(auth_session_key(jti)).await.map_err(|err| {
+      let msg = "failed to get the session from redis";
+      InternalError::new(path.into(), Box::new(err), ErrorType::Internal, false, msg.into())
+    })?;
+
+  Ok(SessionRecord::from_fields(jti, &fields))
+}
+
+/// Records a use of the token in its session: the time, the client ip and user agent
+pub(super) async fn touch_session(
+  r: &DefaultRedisClient,
+  jti: &str,
+  ip: &str,
+  user_agent: &str,
+) -> Result<(), BoxedErr> {
+  let path = "auth.controller.touch_session";
+  let key = auth_session_key(jti);
+  let now = time_get_seconds() as i64;
+  let fields =
+    [("last_seen", now.to_string()), ("ip", ip.into()), ("user_agent", user_agent.into())];
+
+  let mut con = r.get_conn(path).await?;
+  let _: () = pipe()
+    .hset_nx(&key, "first_seen", now)
+    .ignore()
+    .hset_multiple(&key, &fields)
+    .ignore()
+    // a session recorded by the validation already expires with its token
+    .add_command(
+      cmd("EXPIRE").arg(&key).arg(r.tokens.status_fallback_ttl_secs).arg("NX").to_owned(),
+    )
+    .ignore()
+    .query_async(&mut con)
+    .await
+    .map_err(|err| {
+      let msg = "failed to touch the session in redis";
+      InternalError::new(path.into(), Box::new(err), ErrorType::Internal, false, msg.into())
+    })?;
+
+  Ok(())
+}
+
+/// Returns the sessions of the user, the latest used first, a session whose token is
+/// revoked or expired isn't active anymore
+pub(super) async fn list_user_sessions(
+  r: &DefaultRedisClient,
+  user_id: &str,
+) -> Result<Vec<SessionRecord>, BoxedErr> {
+  let path = "auth.controller.list_user_sessions";
+  let ie = |err: BoxedErr, msg: &str| {
+    InternalError::new(path.into(), err, ErrorType::Internal, false, msg.into())
+  };
+
+  let mut con = r.get_conn(path).await?;
+  let tokens: Vec<String> = con
+    .smembers(auth_user_tokens_key(user_id))
+    .await
+    .map_err(|err| ie(Box::new(err), "failed to get the user token set from redis"))?;
+  if tokens.is_empty() {
+    return Ok(vec![]);
+  }
+
+  let mut p = pipe();
+  for jti in &tokens {
+    p.hgetall(auth_session_key(jti)).get(auth_token_status_key(jti));
+  }
+  let res: Vec<(HashMap<String, String>, Option<String>)> = p
+    .query_async(&mut con)
+    .await
+    .map_err(|err| ie(Box::new(err), "failed to get the user sessions from redis"))?;
+
+  let now = time_get_seconds() as i64;
+  let mut sessions = tokens
+    .iter()
+    .zip(res)
+    .filter_map(|(jti, (fields, status))| {
+      let status: TokenStatus = serde_json::from_str(&status?).ok()?;
+      let active = !status.status.revoked && status.exp.is_none_or(|exp| now < exp);
+      active.then(|| SessionRecord::from_fields(jti, &fields)).flatten()
+    })
+    .collect::<Vec<_>>();
+  sessions.sort_by_key(|s| std::cmp::Reverse(s.last_seen));
+
+  Ok(sessions)
+}
+
+/// Revokes the token of a user session, and removes the session, returns false if the
+/// token isn't one of the user tokens
+pub(super) async fn terminate_session(
+  r: &DefaultRedisClient,
+  user_id: &str,
+  jti: &str,
+) -> Result<bool, BoxedErr> {
+  let path = "auth.controller.terminate_session";
+  let ie = |err: BoxedErr, msg: &str| {
+    InternalError::new(path.into(), err, ErrorType::Internal, false, msg.into())
+  };
+
+  let key = auth_user_tokens_key(user_id);
+  let mut con = r.get_conn(path).await?;
+  let owned: bool = con
+    .sismember(&key, jti)
+    .await
+    .map_err(|err| ie(Box::new(err), "failed to check the user token set in redis"))?;
+  if !owned {
+    return Ok(false);
+  }
+  drop(con);
+
+  let exp = r.get_token(jti, path).await?.and_then(|st| st.exp);
+  r.revoke_token(jti, exp).await?;
+
+  let mut con = r.get_conn(path).await?;
+  let _: () = pipe()
+    .del(auth_session_key(jti))
+    .ignore()
+    .srem(&key, jti)
+    .ignore()
+    .query_async(&mut con)
+    .await
+    .map_err(|err| ie(Box::new(err), "failed to remove the session from redis"))?;
+
+  Ok(true)
+}
@@ -1,4 +1,6 @@
-use deadpool_redis::redis::AsyncCommands;
+use std::io::Error;
+
+use deadpool_redis::redis::{cmd, pipe, AsyncCommands};
 use megacommerce_proto::CachedTokenStatus;
 use megacommerce_shared::{
   models::{
@@ -7,15 +9,28 @@ use megacommerce_shared::{
   },
   utils::time::time_get_seconds,
 };
+use ulid::Ulid;
+
+use crate::models::{
+  config::TokensConfig,
+  redis::{
+    auth_device_tokens_key, auth_introspection_lock_key, auth_revoked_tokens_key,
+    auth_user_not_before_key, auth_user_tokens_key,
+  },
+  token::{RevocationEvent, TokenStatus},
+};
 
-use super::redis::{DefaultRedisClient, RedisCheck, RedisClient};
+use super::{
+  redis::{DefaultRedisClient, RedisCheck, RedisClient},
+  session::record_session,
+};
 
 pub(super) async fn check_token(r: &DefaultRedisClient, jti: &str) -> Result<RedisCheck, BoxedErr> {
   let res = r.get_token(jti, "auth.controller.check_token").await?;
 
   match res {
     Some(status) => {
-      if status.revoked {
+      if status.status.revoked {
         return Ok(RedisCheck::Revoked("token got revoked".into()));
       }
 
@@ -25,8 +40,14 @@ pub(super) async fn check_token(r: &DefaultRedisClient, jti: &str) -> Result<Red
   }
 }
 
-// TODO: GET THE user device id
-pub(super) async fn revoke_token(r: &DefaultRedisClient, jti: &str) -> Result<(), BoxedErr> {
+/// Revokes the token, an existing status keeps its device binding, returns true when
+/// a cached token was revoked by this call, an unknown token is still recorded as revoked,
+/// but it's not counted
+pub(super) async fn revoke_token(
+  r: &DefaultRedisClient,
+  jti: &str,
+  exp: Option<i64>,
+) -> Result<bool, BoxedErr> {
   let path = "auth.controller.check_token";
   let res = r.get_token(&jti, path).await?;
 
@@ -34,47 +55,292 @@ pub(super) async fn revoke_token(r: &DefaultRedisClient, jti: &str) -> Result<()
   // So this can happen in this case: only if the user didn't use the token
   // at all, so E,g the user logged in, and immediately closed the website
   // and didn't hit envoy once again, this extreme case, and mostly won't happen
-  if res.is_none() {
+  let Some(mut payload) = res else {
     let last_checked = time_get_seconds() as i64;
-    let payload = CachedTokenStatus { revoked: true, last_checked, dev_id: "".into() };
-    return Ok(r.set_token(jti, &payload, &path).await?);
+    let status = CachedTokenStatus { revoked: true, last_checked, dev_id: "".into() };
+    let payload = TokenStatus { status, exp, ..Default::default() };
+    r.set_token(jti, &payload, &path).await?;
+    publish_revocation(r, jti, &payload, path).await?;
+    return Ok(false);
+  };
+
+  if payload.status.revoked {
+    return Ok(false);
   }
 
-  let mut payload = res.unwrap();
-  payload.revoked = true;
+  payload.status.revoked = true;
+  payload.exp = payload.exp.or(exp);
   r.set_token(jti, &payload, path).await?;
+  publish_revocation(r, jti, &payload, path).await?;
+
+  Ok(true)
+}
+
+/// Records the revoked token in the `auth_revoked_tokens_key` set, and publishes it on the
+/// revocations channel, for the instances that keep the revoked tokens in memory
+async fn publish_revocation(
+  r: &DefaultRedisClient,
+  jti: &str,
+  data: &TokenStatus,
+  path: &str,
+) -> Result<(), BoxedErr> {
+  let ie = |err: BoxedErr, msg: &str| {
+    InternalError::new(path.into(), err, ErrorType::Internal, false, msg.into())
+  };
+
+  let now = time_get_seconds() as i64;
+  let until = now + status_ttl(&r.tokens, data) as i64;
+  let event = serde_json::to_string(&RevocationEvent { jti: jti.into(), until })
+    .map_err(|err| ie(Box::new(err), "failed to serialize RevocationEvent"))?;
+
+  let key = auth_revoked_tokens_key();
+  let mut con = r.get_conn(path).await?;
+  let _: () = pipe()
+    .zadd(&key, jti, until)
+    .ignore()
+    .zrembyscore(&key, "-inf", now)
+    .ignore()
+    .publish(&r.tokens.revocations_channel, event)
+    .ignore()
+    .query_async(&mut con)
+    .await
+    .map_err(|err| ie(Box::new(err), "failed to publish the token revocation"))?;
 
   Ok(())
 }
 
-// TODO: get the device id
-pub(super) async fn mark_checked_ok(r: &DefaultRedisClient, jti: &str) -> Result<(), BoxedErr> {
+/// Records a successful validation of the token, the token is bound to `device_id`
+/// unless it's already bound to a device, and its session is recorded, a None device
+/// binds nothing and starts no session, a revoked token isn't touched, hydra still
+/// reports the tokens revoked here (E,g by an admin) as active, returns false for it
+pub(super) async fn mark_checked_ok(
+  r: &DefaultRedisClient,
+  jti: &str,
+  token_hash: &str,
+  user_id: &str,
+  scopes: &[String],
+  exp: Option<i64>,
+  device_id: Option<&str>,
+) -> Result<bool, BoxedErr> {
   let path = "auth.controller.mark_checked_ok";
-  let res = r.get_token(&jti, path).await?;
+  let updated = update_token_status(r, jti, path, |current| {
+    let mut payload = current.unwrap_or_default();
+    payload.status.last_checked = time_get_seconds() as i64;
+    payload.scopes = Some(scopes.to_vec());
+    payload.exp = exp;
+    payload.token_hash = Some(token_hash.into());
+    if payload.status.dev_id.is_empty() {
+      payload.status.dev_id = device_id.unwrap_or_default().into();
+    }
+    Some(payload)
+  })
+  .await?;
+
+  let session = device_id.is_some();
+  match updated {
+    Some(payload) => index_token(r, jti, user_id, &payload, session, path).await.map(|_| true),
+    None => Ok(false),
+  }
+}
+
+/// Attempts of `update_token_status`, before giving up on a status that keeps changing
+const STATUS_UPDATE_ATTEMPTS: usize = 3;
 
-  if res.is_none() {
-    let payload = CachedTokenStatus {
-      revoked: false,
-      last_checked: time_get_seconds() as i64,
-      dev_id: "".into(),
+/// Updates the cached status of a token that isn't revoked, in a redis transaction watching
+/// the status, so a concurrent revocation is never overwritten, `update` gets the current
+/// status (None if it's not cached), and returns the new one, or None to leave it as is,
+/// returns the written status
+async fn update_token_status<F>(
+  r: &DefaultRedisClient,
+  jti: &str,
+  path: &str,
+  update: F,
+) -> Result<Option<TokenStatus>, BoxedErr>
+where
+  F: Fn(Option<TokenStatus>) -> Option<TokenStatus>,
+{
+  let ie = |err: BoxedErr, msg: &str| {
+    Box::new(InternalError::new(path.into(), err, ErrorType::Internal, false, msg.into()))
+  };
+
+  let key = auth_token_status_key(jti);
+  let mut con = r.get_conn(path).await?;
+  for _ in 0..STATUS_UPDATE_ATTEMPTS {
+    let _: () = cmd("WATCH")
+      .arg(&key)
+      .query_async(&mut con)
+      .await
+      .map_err(|err| ie(Box::new(err), "failed to watch the token status"))?;
+    let res: Option<String> =
+      con.get(&key).await.map_err(|err| ie(Box::new(err), "failed to get the token status"))?;
+
+    let current = res.map(|json| serde_json::from_str::<TokenStatus>(&json));
+    let payload = match current {
+      Some(Err(err)) => Err::<_, BoxedErr>(ie(Box::new(err), "failed to deserialize TokenStatus")),
+      Some(Ok(current)) if current.status.revoked => Ok(None),
+      Some(Ok(current)) => Ok(update(Some(current))),
+      None => Ok(update(None)),
     };
-    r.set_token(jti, &payload, &path).await?;
-    return Ok(());
+    let payload = match payload {
+      Ok(Some(payload)) => payload,
+      res => {
+        // the connection goes back to the pool, it must not keep watching the status
+        let _: () = cmd("UNWATCH")
+          .query_async(&mut con)
+          .await
+          .map_err(|err| ie(Box::new(err), "failed to unwatch the token status"))?;
+        return res.map(|_| None);
+      }
+    };
+
+    let value = serde_json::to_string(&payload)
+      .map_err(|err| ie(Box::new(err), "failed to serialize TokenStatus"))?;
+    // None if the status changed since it was watched
+    let written: Option<()> = pipe()
+      .atomic()
+      .set_ex(&key, value, status_ttl(&r.tokens, &payload))
+      .ignore()
+      .query_async(&mut con)
+      .await
+      .map_err(|err| ie(Box::new(err), "failed to set TokenStatus in redis"))?;
+    if written.is_some() {
+      return Ok(Some(payload));
+    }
   }
 
-  let mut payload = res.unwrap();
-  payload.revoked = false;
-  payload.last_checked = time_get_seconds() as i64;
-  r.set_token(jti, &payload, &path).await?;
+  let msg = "the token status kept changing while being updated";
+  Err(ie(Box::new(Error::other(msg)), msg))
+}
+
+/// Adds the token to the user and the device token sets, so they can be revoked at once,
+/// a set expires with its longest lived token status, and records the token session when
+/// `session`, a token only validated for a shadow rule or a refresh starts no session
+async fn index_token(
+  r: &DefaultRedisClient,
+  jti: &str,
+  user_id: &str,
+  data: &TokenStatus,
+  session: bool,
+  path: &str,
+) -> Result<(), BoxedErr> {
+  let ie = |err: BoxedErr, msg: &str| {
+    InternalError::new(path.into(), err, ErrorType::Internal, false, msg.into())
+  };
+
+  let ttl = status_ttl(&r.tokens, data);
+  let keys = [
+    (user_id, auth_user_tokens_key(user_id)),
+    (&data.status.dev_id, auth_device_tokens_key(&data.status.dev_id)),
+  ];
+
+  let mut p = pipe();
+  for (_, key) in keys.iter().filter(|(id, _)| !id.is_empty()) {
+    p.sadd(key, jti).ignore();
+    p.add_command(cmd("EXPIRE").arg(key).arg(ttl).arg("NX").to_owned()).ignore();
+    p.add_command(cmd("EXPIRE").arg(key).arg(ttl).arg("GT").to_owned()).ignore();
+  }
+  if session {
+    record_session(&mut p, jti, data, ttl);
+  }
+
+  let mut con = r.get_conn(path).await?;
+  let _: () = p
+    .query_async(&mut con)
+    .await
+    .map_err(|err| ie(Box::new(err), "failed to index the token in redis"))?;
 
   Ok(())
 }
 
+/// Binds a cached token status that isn't bound to a device yet, to the device, only an
+/// enforced validation binds a token, so its session is recorded with the device
+pub(super) async fn bind_device(
+  r: &DefaultRedisClient,
+  jti: &str,
+  user_id: &str,
+  device_id: &str,
+) -> Result<(), BoxedErr> {
+  let path = "auth.controller.bind_device";
+  let updated = update_token_status(r, jti, path, |current| {
+    let mut payload = current?;
+    if !payload.status.dev_id.is_empty() || device_id.is_empty() {
+      return None;
+    }
+    payload.status.dev_id = device_id.into();
+    Some(payload)
+  })
+  .await?;
+
+  match updated {
+    Some(payload) => index_token(r, jti, user_id, &payload, true, path).await,
+    None => Ok(()),
+  }
+}
+
+pub(super) async fn revoke_user_tokens(
+  r: &DefaultRedisClient,
+  user_id: &str,
+) -> Result<u32, BoxedErr> {
+  revoke_indexed_tokens(r, &auth_user_tokens_key(user_id), "auth.controller.revoke_user_tokens")
+    .await
+}
+
+pub(super) async fn revoke_device_tokens(
+  r: &DefaultRedisClient,
+  device_id: &str,
+) -> Result<u32, BoxedErr> {
+  let key = auth_device_tokens_key(device_id);
+  revoke_indexed_tokens(r, &key, "auth.controller.revoke_device_tokens").await
+}
+
+/// Revokes the still cached tokens of a token set, a token without a cached status
+/// is past its expiry, so there is nothing to revoke, only the revoked tokens are removed
+/// from the set, so a token indexed meanwhile stays in it
+async fn revoke_indexed_tokens(
+  r: &DefaultRedisClient,
+  key: &str,
+  path: &str,
+) -> Result<u32, BoxedErr> {
+  let ie = |err: BoxedErr, msg: &str| {
+    InternalError::new(path.into(), err, ErrorType::Internal, false, msg.into())
+  };
+
+  let mut con = r.get_conn(path).await?;
+  let tokens: Vec<String> = con
+    .smembers(key)
+    .await
+    .map_err(|err| ie(Box::new(err), "failed to get the token set from redis"))?;
+
+  let mut revoked = 0;
+  let mut removed = vec![];
+  for jti in tokens {
+    let Some(mut payload) = r.get_token(&jti, path).await? else {
+      continue;
+    };
+    if !payload.status.revoked {
+      payload.status.revoked = true;
+      r.set_token(&jti, &payload, path).await?;
+      publish_revocation(r, &jti, &payload, path).await?;
+      revoked += 1;
+    }
+    removed.push(jti);
+  }
+
+  if !removed.is_empty() {
+    let _: () = con
+      .srem(key, &removed)
+      .await
+      .map_err(|err| ie(Box::new(err), "failed to remove the revoked tokens from redis"))?;
+  }
+
+  Ok(revoked)
+}
+
 pub async fn get_token(
   r: &DefaultRedisClient,
   token: &str,
   path: &str,
-) -> Result<Option<CachedTokenStatus>, BoxedErr> {
+) -> Result<Option<TokenStatus>, BoxedErr> {
   let ie = |err: BoxedErr, msg: &str| {
     InternalError::new(path.into(), err, ErrorType::Internal, false, msg.into())
   };
@@ -87,8 +353,8 @@ pub async fn get_token(
 
   match res {
     Some(json_str) => {
-      let token_status: CachedTokenStatus = serde_json::from_str(&json_str)
-        .map_err(|err| ie(Box::new(err), "failed to deserialize CachedTokenStatus"))?;
+      let token_status: TokenStatus = serde_json::from_str(&json_str)
+        .map_err(|err| ie(Box::new(err), "failed to deserialize TokenStatus"))?;
       Ok(Some(token_status))
     }
     None => Ok(None),
@@ -98,7 +364,7 @@ pub async fn get_token(
 pub async fn set_token(
   r: &DefaultRedisClient,
   jti: &str,
-  data: &CachedTokenStatus,
+  data: &TokenStatus,
   path: &str,
 ) -> Result<(), BoxedErr> {
   let ie = |err: BoxedErr, msg: &str| {
@@ -107,12 +373,115 @@ pub async fn set_token(
 
   let mut con = r.get_conn(path).await?;
   let value = serde_json::to_string(data)
-    .map_err(|err| ie(Box::new(err), "failed to serialize CachedTokenStatus"))?;
+    .map_err(|err| ie(Box::new(err), "failed to serialize TokenStatus"))?;
 
   let _: () = con
-    .set(auth_token_status_key(jti), value)
+    .set_ex(auth_token_status_key(jti), value, status_ttl(&r.tokens, data))
+    .await
+    .map_err(|err| ie(Box::new(err), "failed to set TokenStatus in redis"))?;
+
+  Ok(())
+}
+
+pub(super) async fn get_user_not_before(
+  r: &DefaultRedisClient,
+  user_id: &str,
+) -> Result<Option<i64>, BoxedErr> {
+  let path = "auth.controller.get_user_not_before";
+  let mut con = r.get_conn(path).await?;
+  let res: Option<i64> = con.get(auth_user_not_before_key(user_id)).await.map_err(|err| {
+    let msg = "failed to get the user not-before epoch from redis";
+    InternalError::new(path.into(), Box::new(err), ErrorType::Internal, false, msg.into())
+  })?;
+
+  Ok(res)
+}
+
+/// Sets the user not-before epoch to now, which revokes every token issued before it,
+/// it's kept as long as a token without a known `exp` status is kept
+pub(super) async fn set_user_not_before(
+  r: &DefaultRedisClient,
+  user_id: &str,
+) -> Result<i64, BoxedErr> {
+  let path = "auth.controller.set_user_not_before";
+  let now = time_get_seconds() as i64;
+  // every token issued before the epoch is expired by then
+  let ttl = r.tokens.max_token_lifetime_secs + r.tokens.status_grace_secs;
+
+  let mut con = r.get_conn(path).await?;
+  let _: () = con.set_ex(auth_user_not_before_key(user_id), now, ttl).await.map_err(|err| {
+    let msg = "failed to set the user not-before epoch in redis";
+    InternalError::new(path.into(), Box::new(err), ErrorType::Internal, false, msg.into())
+  })?;
+
+  Ok(now)
+}
+
+/// Tries to take the introspection lock of a token, so a single instance introspects it,
+/// the lock expires on its own, in case its holder never releases it, returns the random
+/// owner value the lock is released with when it's taken
+pub(super) async fn lock_introspection(
+  r: &DefaultRedisClient,
+  jti: &str,
+  ttl_ms: u64,
+) -> Result<Option<String>, BoxedErr> {
+  let path = "auth.controller.lock_introspection";
+  let owner = Ulid::new().to_string();
+  let mut con = r.get_conn(path).await?;
+  let res: Option<String> = cmd("SET")
+    .arg(auth_introspection_lock_key(jti))
+    .arg(&owner)
+    .arg("NX")
+    .arg("PX")
+    .arg(ttl_ms)
+    .query_async(&mut con)
     .await
-    .map_err(|err| ie(Box::new(err), "failed to set CachedTokenStatus in redis"))?;
+    .map_err(|err| {
+      let msg = "failed to set the token introspection lock in redis";
+      InternalError::new(path.into(), Box::new(err), ErrorType::Internal, false, msg.into())
+    })?;
+
+  Ok(res.map(|_| owner))
+}
+
+/// Deletes the lock key only if it still holds the owner value, atomically
+const UNLOCK_SCRIPT: &str =
+  r#"if redis.call("GET", KEYS[1]) == ARGV[1] then return redis.call("DEL", KEYS[1]) end return 0"#;
+
+/// Releases the introspection lock of a token, only if it's still held by `owner`, a lock
+/// that expired meanwhile may be taken by another instance already
+pub(super) async fn unlock_introspection(
+  r: &DefaultRedisClient,
+  jti: &str,
+  owner: &str,
+) -> Result<(), BoxedErr> {
+  let path = "auth.controller.unlock_introspection";
+  let mut con = r.get_conn(path).await?;
+  let _: i64 = cmd("EVAL")
+    .arg(UNLOCK_SCRIPT)
+    .arg(1)
+    .arg(auth_introspection_lock_key(jti))
+    .arg(owner)
+    .query_async(&mut con)
+    .await
+    .map_err(|err| {
+      let msg = "failed to delete the token introspection lock from redis";
+      InternalError::new(path.into(), Box::new(err), ErrorType::Internal, false, msg.into())
+    })?;
 
   Ok(())
 }
+
+/// The redis expiry of a token status, it's the time left until the token `exp` plus the
+/// grace period, so a revoked status always outlives the token itself, a revoked status
+/// with an unknown `exp` is kept for the longest token lifetime plus the grace period
+fn status_ttl(tokens: &TokensConfig, data: &TokenStatus) -> u64 {
+  let ttl = match data.exp {
+    Some(exp) => (exp - time_get_seconds() as i64).max(0) as u64 + tokens.status_grace_secs,
+    None if data.status.revoked => tokens.max_token_lifetime_secs + tokens.status_grace_secs,
+    None => tokens.status_fallback_ttl_secs,
+  };
+
+  // redis rejects a zero expiry, E,g an expired token without a grace period
+  ttl.max(1)
+}
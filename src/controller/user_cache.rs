@@ -1,22 +1,39 @@
 use std::sync::Arc;
 
 use deadpool_redis::redis::AsyncCommands;
-use megacommerce_proto::CachedUserData;
 use megacommerce_shared::models::{
   context::Context,
   errors::{BoxedErr, ErrorType, InternalError},
-  redis::auth_user_data_key,
 };
 use serde_json::to_string;
 
-use super::Controller;
+use crate::models::{redis::auth_user_data_by_id_key, user::UserAuthData};
 
+use super::{redis::DefaultRedisClient, Controller};
+
+/// Drops the cached auth data of a user, so it's fetched again from the store
+pub(super) async fn invalidate_auth_cached_user_data(
+  r: &DefaultRedisClient,
+  user_id: &str,
+) -> Result<(), BoxedErr> {
+  let path = "auth.controller.invalidate_auth_cached_user_data";
+  let mut con = r.get_conn(path).await?;
+  let _: () = con.del(auth_user_data_by_id_key(user_id)).await.map_err(|err| {
+    let msg = "failed to delete UserAuthData from redis";
+    InternalError::new(path.into(), Box::new(err), ErrorType::Internal, true, msg.into())
+  })?;
+
+  Ok(())
+}
+
+/// The auth data of a user is cached by the user id (the jwt `sub`) for
+/// `UsersConfig::cache_ttl_secs`, a missing user isn't cached, so it's found once created
 impl Controller {
   pub async fn insert_auth_cached_user_data(
     &self,
     ctx: Arc<Context>,
-    email: &str,
-  ) -> Result<CachedUserData, BoxedErr> {
+    user_id: &str,
+  ) -> Result<Option<UserAuthData>, BoxedErr> {
     let path = "auth.controller.insert_auth_cached_user_data";
     let ie = |err: BoxedErr, msg: &str| InternalError {
       err,
@@ -29,27 +46,31 @@ impl Controller {
       .store
       .get()
       .await
-      .user_get_auth_data(ctx, email)
+      .user_get_auth_data(ctx, user_id)
       .await
       .map_err(|err| ie(Box::new(err), "failed to get user auth data"))?;
+    let Some(data) = data else {
+      return Ok(None);
+    };
 
     let mut con = self.redis.get_conn(&path).await?;
 
     let payload =
-      to_string(&data).map_err(|err| ie(Box::new(err), "failed to serialize CachedUserData"))?;
+      to_string(&data).map_err(|err| ie(Box::new(err), "failed to serialize UserAuthData"))?;
 
+    let ttl = self.service_config.users.cache_ttl_secs.max(1);
     let _: () = con
-      .set(auth_user_data_key(email), payload)
+      .set_ex(auth_user_data_by_id_key(user_id), payload, ttl)
       .await
-      .map_err(|err| ie(Box::new(err), "failed to set CachedUserStatus in redis"))?;
+      .map_err(|err| ie(Box::new(err), "failed to set UserAuthData in redis"))?;
 
-    Ok(data)
+    Ok(Some(data))
   }
 
   pub async fn get_auth_cached_user_data(
     &self,
-    email: &str,
-  ) -> Result<Option<CachedUserData>, BoxedErr> {
+    user_id: &str,
+  ) -> Result<Option<UserAuthData>, BoxedErr> {
     let path = "auth.controller.get_auth_cached_user_data";
     let ie = |err: BoxedErr, msg: &str| InternalError {
       err,
@@ -61,14 +82,14 @@ impl Controller {
 
     let mut con = self.redis.get_conn(&path).await?;
     let res: Option<String> = con
-      .get(auth_user_data_key(email))
+      .get(auth_user_data_by_id_key(user_id))
       .await
       .map_err(|err| ie(Box::new(err), "failed to get user data from redis"))?;
 
     match res {
       Some(json_str) => {
-        let data: CachedUserData = serde_json::from_str(&json_str)
-          .map_err(|err| ie(Box::new(err), "failed to deserialize CachedUserData"))?;
+        let data: UserAuthData = serde_json::from_str(&json_str)
+          .map_err(|err| ie(Box::new(err), "failed to deserialize UserAuthData"))?;
         Ok(Some(data))
       }
       None => Ok(None),
@@ -78,12 +99,13 @@ impl Controller {
   pub async fn get_or_insert_auth_cached_user_data(
     &self,
     ctx: Arc<Context>,
-    email: &str,
-  ) -> Result<CachedUserData, BoxedErr> {
-    let user = self.get_auth_cached_user_data(email).await?;
+    user_id: &str,
+  ) -> Result<Option<UserAuthData>, BoxedErr> {
+    let user = self.get_auth_cached_user_data(user_id).await?;
+    // an entry cached before the user type was recorded is refreshed
     match user {
-      Some(user) => Ok(user),
-      None => self.insert_auth_cached_user_data(ctx, email).await,
+      Some(user) if !user.user_type.is_empty() => Ok(Some(user)),
+      _ => self.insert_auth_cached_user_data(ctx, user_id).await,
     }
   }
 }
@@ -0,0 +1,746 @@
+use std::sync::Arc;
+
+use megacommerce_proto::JwtClaims;
+use megacommerce_shared::{
+  models::{context::Context, errors::BoxedErr},
+  utils::time::time_get_seconds,
+};
+use tokio::try_join;
+
+use crate::{
+  models::{
+    config::{DeviceMismatchPolicy, OutageMode, SessionLimitPolicy},
+    routes::RouteEntry,
+    session::SessionRecord,
+    token::TokenStatus,
+  },
+  utils::token::token_hash,
+};
+
+use super::{
+  access::TokenState,
+  claims::{check_claims, check_token_match, ClaimsViolation},
+  hydra::{HydraClient, HydraValidation},
+  jwks::JwksHydraClient,
+  redis::{RedisCheck, RedisClient},
+  Controller,
+};
+
+impl Controller {
+  /// Checks the forwarded claims locally, then validates the request token against the
+  /// redis cached status, and the user not-before epoch, then verifies a JWT token locally
+  /// when JWKS verification is enabled, and against hydra when the cached status is missing
+  /// or stale, or when the token is opaque, a status nearing staleness is refreshed in the
+  /// background instead, if hydra is unreachable, the outage mode of the route decides,
+  /// the device binding and the sessions are only changed when the enforced rule of the
+  /// route is protected, a shadow-only route just gets the state its shadow rule records
+  pub(super) async fn validate_token_state(
+    &self,
+    route: &RouteEntry,
+    claims: &JwtClaims,
+    raw_token: Option<&str>,
+    device_id: Option<&str>,
+    ctx: &Arc<Context>,
+  ) -> Result<TokenState, BoxedErr> {
+    let token = claims.jti.as_str();
+    if token.is_empty() {
+      return Ok(TokenState::Missing);
+    }
+
+    let enforced = route.rule.protected;
+    let now = time_get_seconds() as i64;
+    if let Err(violation) = check_claims(&self.service_config.claims, claims, now) {
+      return Ok(self.rejected(violation));
+    }
+
+    if self.revocations.contains(token) {
+      self.metrics.incr("auth_local_revocation_hits_total", &[]);
+      return Ok(TokenState::Invalid);
+    }
+
+    let get_session = async {
+      match self.service_config.sessions.enabled {
+        true => self.redis.get_session(token).await,
+        false => Ok(None),
+      }
+    };
+    let (check, not_before, session) = try_join!(
+      self.redis.check_token(token),
+      self.redis.get_user_not_before(&claims.sub),
+      get_session
+    )?;
+
+    let status = match check {
+      RedisCheck::Revoked(_) => return Ok(TokenState::Invalid),
+      RedisCheck::Allowed { status } => status,
+    };
+
+    // a token without iat can't be told apart from one issued before the epoch
+    let iat = claims.iat.as_ref().map(|t| t.seconds);
+    if not_before.is_some_and(|nb| iat.is_none_or(|iat| iat < nb)) {
+      return Ok(TokenState::Invalid);
+    }
+
+    if self.session_idle(claims, session.as_ref(), ctx, now, enforced).await {
+      return Ok(TokenState::IdleTimeout);
+    }
+
+    let device = device_id.unwrap_or_default();
+    let mismatch = match &status {
+      // a missing device is a mismatch too, otherwise dropping the header skips the check
+      Some(st) if !st.status.dev_id.is_empty() => st.status.dev_id != device,
+      Some(_) if !device.is_empty() && enforced => {
+        // bound on the first use from a known device
+        self.redis.bind_device(token, &claims.sub, device).await.ok();
+        false
+      }
+      _ => false,
+    };
+
+    let policy = route.device_mismatch.unwrap_or(self.service_config.devices.mismatch);
+    if mismatch {
+      self.metrics.incr("auth_device_mismatches_total", &[("policy", &policy.to_string())]);
+      tracing::warn!(
+        jti = %token,
+        bound_device = %status.as_ref().map(|st| st.status.dev_id.as_str()).unwrap_or_default(),
+        device = %device,
+        path = %route.path,
+        policy = %policy,
+        "the token is used from another device than it's bound to"
+      );
+      if policy == DeviceMismatchPolicy::Deny {
+        return Ok(TokenState::DeviceMismatch);
+      }
+    }
+
+    // a shadow-only route neither binds the token to the device, nor starts its session
+    let bound = enforced.then_some(device);
+    let mut state = self.status_token_state(route, claims, raw_token, status, bound, now).await;
+    if let Ok(TokenState::Valid { device_mismatch, last_active, .. }) = &mut state {
+      *device_mismatch = mismatch && policy == DeviceMismatchPolicy::Flag;
+      *last_active = session.as_ref().map(SessionRecord::last_active);
+      // a session without a record is a new one, it counts against the limit of its user
+      let sessions = &self.service_config.sessions;
+      let new_session = sessions.enabled && session.is_none();
+      if new_session && !self.enforce_session_limit(claims, ctx, enforced).await {
+        return Ok(TokenState::SessionLimitExceeded);
+      }
+      if enforced {
+        self.touch_session(token, session.as_ref(), ctx, now).await;
+      }
+    }
+    state
+  }
+
+  /// Keeps the active sessions of the token user within the first matching limit, by
+  /// terminating the least recently started ones, or the new session, returns false if
+  /// the new session is terminated, the limit isn't enforced if redis or the store fails,
+  /// when not `enforced` no session is terminated, only the decision is returned
+  async fn enforce_session_limit(
+    &self,
+    claims: &JwtClaims,
+    ctx: &Arc<Context>,
+    enforced: bool,
+  ) -> bool {
+    let sessions = &self.service_config.sessions;
+    if sessions.limits.is_empty() {
+      return true;
+    }
+
+    let (user_id, jti) = (claims.sub.as_str(), claims.jti.as_str());
+    let active = async {
+      // a missing user isn't limited, its requests are denied by the rules needing it
+      let Some(user) = self.get_or_insert_auth_cached_user_data(ctx.clone(), user_id).await? else {
+        return Ok(None);
+      };
+      let Some(limit) = sessions.limits.iter().find(|l| l.matches(&user)) else {
+        return Ok(None);
+      };
+      let mut others = self.redis.list_user_sessions(user_id).await?;
+      others.retain(|s| s.jti != jti);
+      Ok::<_, BoxedErr>(Some((limit.max_sessions.max(1), others)))
+    };
+    let (max, mut others) = match active.await {
+      Ok(Some((max, others))) if others.len() >= max => (max, others),
+      Ok(_) => return true,
+      Err(err) => {
+        tracing::warn!(err = %err, user_id = %user_id, "failed to enforce the sessions limit");
+        return true;
+      }
+    };
+
+    let policy = sessions.on_limit;
+    if !enforced {
+      return policy != SessionLimitPolicy::DenyNewest;
+    }
+
+    self.metrics.incr("auth_session_limit_total", &[("policy", &policy.to_string())]);
+    tracing::info!(
+      user_id = %user_id,
+      jti = %jti,
+      sessions = others.len() + 1,
+      max = max,
+      policy = %policy,
+      "the user is over the concurrent sessions limit"
+    );
+
+    if policy == SessionLimitPolicy::DenyNewest {
+      self.redis.terminate_session(user_id, jti).await.ok();
+      return false;
+    }
+
+    others.sort_by_key(|s| s.first_seen);
+    for session in others.iter().take(others.len() + 1 - max) {
+      self.redis.terminate_session(user_id, &session.jti).await.ok();
+    }
+    true
+  }
+
+  /// Checks if the session went unused past the idle timeout of its user: the strictest
+  /// timeout of the user roles, or `SessionsConfig::idle_timeout_secs`, an idle session is
+  /// terminated when `enforced`, otherwise it's only reported, and the shadow rule of the
+  /// route records the decision, the timeout isn't enforced if the store fails
+  async fn session_idle(
+    &self,
+    claims: &JwtClaims,
+    session: Option<&SessionRecord>,
+    ctx: &Arc<Context>,
+    now: i64,
+    enforced: bool,
+  ) -> bool {
+    let sessions = &self.service_config.sessions;
+    let Some(session) = session.filter(|s| s.last_active() > 0) else {
+      return false;
+    };
+
+    // the user roles are only looked up once the strictest timeout configured is exceeded
+    let idle = now - session.last_active();
+    let timeouts = &sessions.role_idle_timeouts;
+    let strictest =
+      timeouts.values().chain([&sessions.idle_timeout_secs]).filter(|t| **t > 0).min();
+    if strictest.is_none_or(|t| idle <= *t as i64) {
+      return false;
+    }
+
+    let timeout = if timeouts.is_empty() {
+      sessions.idle_timeout_secs
+    } else {
+      match self.get_or_insert_auth_cached_user_data(ctx.clone(), &claims.sub).await {
+        Ok(user) => user
+          .map(|u| u.data.roles)
+          .unwrap_or_default()
+          .split(',')
+          .filter_map(|role| timeouts.get(role.trim()).copied())
+          .filter(|t| *t > 0)
+          .min()
+          .unwrap_or(sessions.idle_timeout_secs),
+        Err(err) => {
+          tracing::warn!(
+            err = %err,
+            user_id = %claims.sub,
+            "failed to get the session idle timeout"
+          );
+          return false;
+        }
+      }
+    };
+    if timeout == 0 || idle <= timeout as i64 {
+      return false;
+    }
+    if !enforced {
+      return true;
+    }
+
+    self.metrics.incr("auth_session_idle_timeouts_total", &[]);
+    tracing::info!(
+      user_id = %claims.sub,
+      jti = %claims.jti,
+      idle = idle,
+      timeout = timeout,
+      "the session is idle for too long"
+    );
+    self.redis.terminate_session(&claims.sub, &claims.jti).await.ok();
+    true
+  }
+
+  /// Records the use of the token in its session, at most once per touch interval,
+  /// unless the client ip or user agent changes
+  async fn touch_session(
+    &self,
+    jti: &str,
+    session: Option<&SessionRecord>,
+    ctx: &Context,
+    now: i64,
+  ) {
+    let sessions = &self.service_config.sessions;
+    let ip = ctx.ip_address.as_str();
+    let due = session.is_none_or(|s| {
+      now - s.last_seen >= sessions.touch_interval_secs as i64
+        || s.ip != ip
+        || s.user_agent != ctx.user_agent
+    });
+
+    if sessions.enabled && due {
+      self.redis.touch_session(jti, ip, &ctx.user_agent).await.ok();
+    }
+  }
+
+  /// Decides on a token that isn't revoked, from its cached status, local verification
+  /// or introspection
+  async fn status_token_state(
+    &self,
+    route: &RouteEntry,
+    claims: &JwtClaims,
+    raw_token: Option<&str>,
+    status: Option<TokenStatus>,
+    device: Option<&str>,
+    now: i64,
+  ) -> Result<TokenState, BoxedErr> {
+    let token = claims.jti.as_str();
+    let jwt = raw_token.filter(|t| JwksHydraClient::is_jwt(t));
+    let mut revocation_check = false;
+    if let (Some(jwks), Some(jwt)) = (&self.jwks, jwt) {
+      // a failed local verification isn't cached, the jti of a forged token may be a real one
+      let (sub, scopes, exp) = match jwks.validate_token(jwt).await {
+        Ok(HydraValidation::Valid { sub, jti, scopes, exp, .. }) => {
+          if let Err(violation) = check_token_match(claims, &sub, &jti) {
+            return Ok(self.rejected(violation));
+          }
+          (sub, scopes, exp)
+        }
+        Ok(HydraValidation::Invalid(_)) => return Ok(TokenState::Invalid),
+        Err(err) => return self.degraded_token_state(route, status.as_ref(), err),
+      };
+
+      let interval =
+        route.revalidation_interval_secs.unwrap_or(jwks.config.introspection_interval_secs) as i64;
+      let due = match &status {
+        Some(st) => now - st.status.last_checked > interval,
+        None => true,
+      };
+      if interval == 0 || !due {
+        if status.is_none() {
+          // recorded on first use, so the token can be revoked with the user or device tokens
+          let hash = token_hash(jwt);
+          let recorded =
+            self.redis.mark_checked_ok(token, &hash, &sub, &scopes, Some(exp), device).await;
+          if let Ok(false) = recorded {
+            return Ok(TokenState::Invalid);
+          }
+        } else if interval > 0 {
+          self.refresh_ahead(claims, jwt, status.as_ref(), interval, now);
+        }
+        return Ok(TokenState::Valid {
+          scopes,
+          degraded: false,
+          device_mismatch: false,
+          last_active: None,
+        });
+      }
+      revocation_check = true;
+    }
+
+    let interval = route
+      .revalidation_interval_secs
+      .unwrap_or(self.service_config.tokens.revalidation_interval_secs) as i64;
+    let needs_hydra = revocation_check
+      || match &status {
+        Some(st) => {
+          now - st.status.last_checked > interval
+            || st.exp.is_some_and(|exp| now >= exp)
+            || (route.needs_scopes() && st.scopes.is_none())
+        }
+        None => true,
+      };
+
+    if !needs_hydra {
+      if let Some(raw_token) = raw_token {
+        self.refresh_ahead(claims, raw_token, status.as_ref(), interval, now);
+      }
+      let scopes = status.and_then(|st| st.scopes).unwrap_or_default(); // Cached as valid
+      return Ok(TokenState::Valid {
+        scopes,
+        degraded: false,
+        device_mismatch: false,
+        last_active: None,
+      });
+    }
+
+    // the jti is never introspected in place of the token, hydra would report it inactive,
+    // and the jti would be revoked
+    let Some(raw_token) = raw_token else {
+      return Ok(TokenState::Missing);
+    };
+    match self.introspector.introspect(claims, raw_token, device).await {
+      Ok(HydraValidation::Valid { sub, jti, scopes, .. }) => {
+        match check_token_match(claims, &sub, &jti) {
+          Ok(()) => Ok(TokenState::Valid {
+            scopes,
+            degraded: false,
+            device_mismatch: false,
+            last_active: None,
+          }),
+          Err(violation) => Ok(self.rejected(violation)),
+        }
+      }
+      Ok(HydraValidation::Invalid(_)) => Ok(TokenState::Invalid),
+      Err(err) => self.degraded_token_state(route, status.as_ref(), err),
+    }
+  }
+
+  /// Rejects the claims of a token, counted by the violation
+  fn rejected(&self, violation: ClaimsViolation) -> TokenState {
+    self.metrics.incr("auth_claims_rejections_total", &[("reason", violation.as_str())]);
+    TokenState::Rejected(violation)
+  }
+
+  /// Schedules a background revalidation of a token whose cached status is about to turn
+  /// stale, so a later request doesn't wait on hydra for it
+  fn refresh_ahead(
+    &self,
+    claims: &JwtClaims,
+    token: &str,
+    status: Option<&TokenStatus>,
+    interval: i64,
+    now: i64,
+  ) {
+    if status.is_some_and(|st| self.refresh_due(st, interval, now)) {
+      self.refresher.schedule(claims, token);
+    }
+  }
+
+  /// Checks if the status is within the refresh-ahead window of its interval, the window
+  /// is at most half the interval, so a short interval isn't refreshed on every request
+  fn refresh_due(&self, status: &TokenStatus, interval: i64, now: i64) -> bool {
+    let ahead = (self.service_config.tokens.refresh_ahead_secs as i64).min(interval / 2);
+    ahead > 0 && now - status.status.last_checked > interval - ahead
+  }
+
+  /// Decides on a token that couldn't be validated because hydra is unreachable,
+  /// a token allowed this way is flagged as degraded
+  fn degraded_token_state(
+    &self,
+    route: &RouteEntry,
+    status: Option<&TokenStatus>,
+    err: BoxedErr,
+  ) -> Result<TokenState, BoxedErr> {
+    let tokens = &self.service_config.tokens;
+    let mode = route.outage.unwrap_or(tokens.outage_mode);
+    let now = time_get_seconds() as i64;
+
+    let allowed = match mode {
+      OutageMode::FailClosed => false,
+      OutageMode::FailOpenStale => status.is_some_and(|st| {
+        now - st.status.last_checked <= tokens.outage_stale_window_secs as i64
+          && st.exp.is_none_or(|exp| now < exp)
+      }),
+      OutageMode::FailOpen => true,
+    };
+
+    let mode_label = mode.to_string();
+    let decision = if allowed { "allow" } else { "deny" };
+    let labels = [("mode", mode_label.as_str()), ("decision", decision)];
+    self.metrics.incr("auth_degraded_decisions_total", &labels);
+    if !allowed {
+      return Err(err);
+    }
+
+    tracing::warn!(
+      err = %err,
+      mode = %mode,
+      path = %route.path,
+      "hydra is unreachable, the token is allowed in degraded mode"
+    );
+    let scopes = status.and_then(|st| st.scopes.clone()).unwrap_or_default();
+    Ok(TokenState::Valid { scopes, degraded: true, device_mismatch: false, last_active: None })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+
+  use deadpool_redis::redis::AsyncCommands;
+  use megacommerce_proto::{
+    service::auth::v3::{check_response::HttpResponse, CheckResponse, OkHttpResponse},
+    CachedTokenStatus, Timestamp,
+  };
+  use megacommerce_shared::models::errors::{ErrorType, InternalError};
+  use serde_json::to_string;
+  use tonic::{async_trait, Response};
+  use ulid::Ulid;
+
+  use crate::models::{
+    config::{Config as ServiceConfig, SessionLimit, SessionLimitPolicy},
+    redis::auth_user_data_by_id_key,
+    routes::RouteRule,
+    user::UserAuthData,
+  };
+
+  use super::*;
+
+  /// Stands in for hydra during an outage
+  #[derive(Debug)]
+  struct UnreachableHydra;
+
+  #[async_trait]
+  impl HydraClient for UnreachableHydra {
+    async fn validate_token(&self, _token: &str) -> Result<HydraValidation, BoxedErr> {
+      let err = Box::new(std::io::Error::other("connection refused"));
+      let msg = "failed to introspect the token".to_string();
+      Err(Box::new(InternalError::new("test".into(), err, ErrorType::Internal, true, msg)))
+    }
+  }
+
+  /// Reports every token active, as issued to `user-2`
+  #[derive(Debug)]
+  struct OtherUserHydra;
+
+  #[async_trait]
+  impl HydraClient for OtherUserHydra {
+    async fn validate_token(&self, _token: &str) -> Result<HydraValidation, BoxedErr> {
+      Ok(HydraValidation::Valid {
+        sub: "user-2".into(),
+        jti: String::new(),
+        exp: 0,
+        scopes: vec![],
+        client_id: String::new(),
+        aud: vec![],
+      })
+    }
+  }
+
+  fn controller(outage_mode: OutageMode) -> Controller {
+    let mut config = ServiceConfig::default();
+    config.tokens.outage_mode = outage_mode;
+    config.tokens.revalidation_interval_secs = 300;
+    config.tokens.outage_stale_window_secs = 3600;
+    Controller::stub(config, Arc::new(UnreachableHydra))
+  }
+
+  fn claims() -> JwtClaims {
+    JwtClaims { sub: "user-1".into(), jti: "jti-1".into(), ..Default::default() }
+  }
+
+  /// A cached status due for revalidation, last validated `checked_ago` seconds ago
+  fn status(now: i64, checked_ago: i64) -> TokenStatus {
+    TokenStatus {
+      status: CachedTokenStatus { last_checked: now - checked_ago, ..Default::default() },
+      scopes: Some(vec!["products.read".into()]),
+      ..Default::default()
+    }
+  }
+
+  async fn decide(
+    controller: &Controller,
+    route: &RouteEntry,
+    status: Option<TokenStatus>,
+    now: i64,
+  ) -> Result<TokenState, BoxedErr> {
+    controller.status_token_state(route, &claims(), Some("token-1"), status, Some(""), now).await
+  }
+
+  fn is_degraded(state: &TokenState) -> bool {
+    matches!(state, TokenState::Valid { degraded: true, .. })
+  }
+
+  #[tokio::test]
+  async fn fail_closed_denies_during_an_outage() {
+    let controller = controller(OutageMode::FailClosed);
+    let now = time_get_seconds() as i64;
+    let res = decide(&controller, &RouteEntry::default(), Some(status(now, 600)), now).await;
+    assert!(res.is_err());
+  }
+
+  #[tokio::test]
+  async fn fail_open_stale_allows_a_token_validated_within_the_window() {
+    let controller = controller(OutageMode::FailOpenStale);
+    let now = time_get_seconds() as i64;
+    let state = decide(&controller, &RouteEntry::default(), Some(status(now, 600)), now).await;
+    match state.unwrap() {
+      TokenState::Valid { scopes, degraded, .. } => {
+        assert!(degraded);
+        assert_eq!(scopes, vec!["products.read"]);
+      }
+      state => panic!("expected a degraded token, got {:?}", state),
+    }
+  }
+
+  #[tokio::test]
+  async fn fail_open_stale_denies_a_token_validated_outside_the_window() {
+    let controller = controller(OutageMode::FailOpenStale);
+    let now = time_get_seconds() as i64;
+    let route = RouteEntry::default();
+    assert!(decide(&controller, &route, Some(status(now, 7200)), now).await.is_err());
+    assert!(decide(&controller, &route, None, now).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn fail_open_stale_denies_an_expired_token() {
+    let controller = controller(OutageMode::FailOpenStale);
+    let now = time_get_seconds() as i64;
+    let status = TokenStatus { exp: Some(now - 1), ..status(now, 600) };
+    assert!(decide(&controller, &RouteEntry::default(), Some(status), now).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn a_fail_open_route_overrides_the_outage_mode() {
+    let controller = controller(OutageMode::FailClosed);
+    let now = time_get_seconds() as i64;
+    let route = RouteEntry { outage: Some(OutageMode::FailOpen), ..Default::default() };
+    let state = decide(&controller, &route, None, now).await.unwrap();
+    assert!(is_degraded(&state));
+  }
+
+  fn ok_response() -> Response<CheckResponse> {
+    let ok = HttpResponse::OkResponse(OkHttpResponse::default());
+    Response::new(CheckResponse { http_response: Some(ok), ..Default::default() })
+  }
+
+  fn flags(res: &Response<CheckResponse>) -> Vec<String> {
+    match res.get_ref().http_response.as_ref() {
+      Some(HttpResponse::OkResponse(ok)) => ok
+        .response_headers_to_add
+        .iter()
+        .filter_map(|h| h.header.as_ref())
+        .map(|h| h.key.clone())
+        .collect(),
+      _ => vec![],
+    }
+  }
+
+  #[tokio::test]
+  async fn a_degraded_token_is_flagged() {
+    let controller = controller(OutageMode::FailOpenStale);
+    let now = time_get_seconds() as i64;
+    let state = decide(&controller, &RouteEntry::default(), Some(status(now, 600)), now).await;
+
+    let mut res = ok_response();
+    Controller::flag_token_state(&mut res, &state.unwrap());
+    assert_eq!(flags(&res), vec!["x-auth-degraded"]);
+
+    let mut res = ok_response();
+    let valid = TokenState::Valid {
+      scopes: vec![],
+      degraded: false,
+      device_mismatch: false,
+      last_active: None,
+    };
+    Controller::flag_token_state(&mut res, &valid);
+    assert!(flags(&res).is_empty());
+  }
+
+  #[tokio::test]
+  async fn a_token_not_matching_its_claims_is_rejected() {
+    let controller = Controller::stub(ServiceConfig::default(), Arc::new(OtherUserHydra));
+    let now = time_get_seconds() as i64;
+    let state = decide(&controller, &RouteEntry::default(), None, now).await.unwrap();
+    assert_eq!(state, TokenState::Rejected(ClaimsViolation::TokenMismatch));
+  }
+
+  #[tokio::test]
+  async fn a_claims_only_request_is_never_introspected() {
+    // a fail open route would allow the token, had hydra been asked
+    let controller = controller(OutageMode::FailOpen);
+    let (now, claims, route) = (time_get_seconds() as i64, claims(), RouteEntry::default());
+    let state = controller.status_token_state(&route, &claims, None, None, Some(""), now).await;
+    assert_eq!(state.unwrap(), TokenState::Missing);
+
+    // a fresh cached status doesn't need hydra
+    let status = Some(status(now, 10));
+    let state = controller.status_token_state(&route, &claims, None, status, Some(""), now).await;
+    assert!(matches!(state.unwrap(), TokenState::Valid { degraded: false, .. }));
+  }
+
+  #[tokio::test]
+  async fn the_refresh_ahead_window_is_clamped_to_the_interval() {
+    let mut config = ServiceConfig::default();
+    config.tokens.refresh_ahead_secs = 60;
+    let controller = Controller::stub(config, Arc::new(UnreachableHydra));
+    let now = time_get_seconds() as i64;
+
+    let cases = [
+      // interval, checked ago, due
+      (300, 200, false),
+      (300, 250, true),
+      (10, 0, false),
+      (10, 4, false),
+      (10, 6, true),
+      (1, 1, false),
+    ];
+    for (interval, checked_ago, due) in cases {
+      let status = status(now, checked_ago);
+      assert_eq!(
+        controller.refresh_due(&status, interval, now),
+        due,
+        "{} {}",
+        interval,
+        checked_ago
+      );
+    }
+  }
+
+  /// Reports every token active, without a subject or a token id, so it matches any claims
+  #[derive(Debug)]
+  struct ActiveHydra;
+
+  #[async_trait]
+  impl HydraClient for ActiveHydra {
+    async fn validate_token(&self, _token: &str) -> Result<HydraValidation, BoxedErr> {
+      Ok(HydraValidation::Valid {
+        sub: String::new(),
+        jti: String::new(),
+        exp: time_get_seconds() as i64 + 3600,
+        scopes: vec![],
+        client_id: String::new(),
+        aud: vec![],
+      })
+    }
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a redis server at AUTH_TEST_REDIS_URL, or redis://127.0.0.1:6379"]
+  async fn a_shadow_only_use_doesnt_start_a_session() {
+    let url = env::var("AUTH_TEST_REDIS_URL").unwrap_or("redis://127.0.0.1:6379".into());
+    let mut config = ServiceConfig::default();
+    config.sessions.enabled = true;
+    config.sessions.limits = vec![SessionLimit { user_type: None, role: None, max_sessions: 1 }];
+    config.sessions.on_limit = SessionLimitPolicy::DenyNewest;
+    config.tokens.introspection_lock_ms = 0;
+    let controller = Controller::stub_on(config, Arc::new(ActiveHydra), &url);
+
+    // a fresh user, so a rerun doesn't see the sessions of the previous one
+    let (user_id, now) = (Ulid::new().to_string(), time_get_seconds() as i64);
+    let user = UserAuthData { user_type: "customer".into(), ..Default::default() };
+    let mut con = controller.redis.get_conn("test").await.unwrap();
+    let _: () =
+      con.set(auth_user_data_by_id_key(&user_id), to_string(&user).unwrap()).await.unwrap();
+
+    // the user is at the limit with a session on another device
+    let (other, exp) = (Ulid::new().to_string(), Some(now + 3600));
+    let redis = &controller.redis;
+    redis.mark_checked_ok(&other, "other", &user_id, &[], exp, Some("device-1")).await.unwrap();
+    redis.touch_session(&other, "10.0.0.1", "test").await.unwrap();
+
+    let claims = JwtClaims {
+      sub: user_id,
+      jti: Ulid::new().to_string(),
+      exp: exp.map(|seconds| Timestamp { seconds, nanos: 0 }),
+      ..Default::default()
+    };
+    let protected_rule = RouteRule { protected: true, ..Default::default() };
+    let shadow_only = RouteEntry { shadow: Some(protected_rule.clone()), ..Default::default() };
+    let protected = RouteEntry { rule: protected_rule, ..Default::default() };
+    let ctx = Arc::new(Context::default());
+
+    // the shadow rule records the deny, the token starts no session
+    let device = Some("device-2");
+    let state = controller.validate_token_state(&shadow_only, &claims, Some("token"), device, &ctx);
+    assert_eq!(state.await.unwrap(), TokenState::SessionLimitExceeded);
+    assert!(redis.get_session(&claims.jti).await.unwrap().is_none());
+
+    // so the first protected use is still a new session over the limit
+    let state = controller.validate_token_state(&protected, &claims, Some("token"), device, &ctx);
+    assert_eq!(state.await.unwrap(), TokenState::SessionLimitExceeded);
+  }
+}
@@ -1,6 +1,7 @@
 pub mod common;
 pub mod controller;
 pub mod models;
+pub mod proto;
 pub mod server;
 pub mod store;
 pub mod utils;
@@ -1,9 +1,38 @@
+use std::collections::HashMap;
+
 use derive_more::Display;
 use serde::Deserialize;
 
+use super::{policy::Policy, routes::RouteEntry, user::UserAuthData};
+
 #[derive(Clone, Debug, Default, Deserialize, Display)]
+#[display(
+  "Config: {service} {network} {hydra} {tokens} {claims} {users} {devices} {sessions} {routes} {jwks} {admin}"
+)]
 pub struct Config {
   pub service: ServiceConfig,
+  #[serde(default)]
+  pub network: NetworkConfig,
+  #[serde(default)]
+  pub hydra: HydraConfig,
+  #[serde(default)]
+  pub tokens: TokensConfig,
+  #[serde(default)]
+  pub claims: ClaimsConfig,
+  #[serde(default)]
+  pub users: UsersConfig,
+  #[serde(default)]
+  pub devices: DevicesConfig,
+  #[serde(default)]
+  pub sessions: SessionsConfig,
+  #[serde(default)]
+  pub routes: RoutesConfig,
+  #[serde(default)]
+  pub jwks: JwksConfig,
+  #[serde(default)]
+  pub policies: Vec<Policy>,
+  #[serde(default)]
+  pub admin: AdminConfig,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Display)]
@@ -13,3 +42,413 @@ pub struct ServiceConfig {
   pub service_grpc_url: String,
   pub common_service_grpc_url: String,
 }
+
+/// How the client ip is resolved from the check request
+#[derive(Clone, Debug, Default, Deserialize, Display)]
+#[display("NetworkConfig: {xff_trusted_hops}")]
+#[serde(default)]
+pub struct NetworkConfig {
+  /// the trusted proxies in front of envoy appending to x-forwarded-for (count envoy itself
+  /// if it appends the peer address, E,g with `use_remote_address`), 0 trusts none of the
+  /// entries, and uses the downstream peer address of envoy
+  pub xff_trusted_hops: usize,
+}
+
+/// Timeouts, retries and the circuit breaker of the hydra introspection client
+#[derive(Clone, Debug, Deserialize, Display)]
+#[display(
+  "HydraConfig: {connect_timeout_ms} {request_timeout_ms} {retries} {retry_backoff_ms} {retry_budget_ms} {breaker_failure_threshold} {breaker_open_secs}"
+)]
+#[serde(default)]
+pub struct HydraConfig {
+  pub connect_timeout_ms: u64,
+  pub request_timeout_ms: u64,
+  /// retries of a failed introspection, on top of the first attempt
+  pub retries: u32,
+  /// the first retry waits between this and twice this, doubled on every retry
+  pub retry_backoff_ms: u64,
+  /// the total time of an introspection, retries and backoff included, an attempt is cut
+  /// short and no retry is made past it, keep it under the envoy ext_authz timeout
+  pub retry_budget_ms: u64,
+  /// consecutive failed introspections (after their retries) that open the circuit
+  pub breaker_failure_threshold: u32,
+  /// how long the circuit stays open before a probe introspection is let through
+  pub breaker_open_secs: u64,
+}
+
+impl Default for HydraConfig {
+  fn default() -> Self {
+    Self {
+      connect_timeout_ms: 500,
+      request_timeout_ms: 2000,
+      retries: 2,
+      retry_backoff_ms: 50,
+      retry_budget_ms: 2500,
+      breaker_failure_threshold: 5,
+      breaker_open_secs: 30,
+    }
+  }
+}
+
+/// How the cached token status is kept fresh
+#[derive(Clone, Debug, Deserialize, Display)]
+#[display(
+  "TokensConfig: {revalidation_interval_secs} {status_grace_secs} {status_fallback_ttl_secs} {max_token_lifetime_secs} {local_revocations} {revocations_channel} {user_events_channel} {not_before_events:?} {outage_mode} {outage_stale_window_secs} {introspection_lock_ms} {refresh_ahead_secs} {refresh_concurrency}"
+)]
+#[serde(default)]
+pub struct TokensConfig {
+  /// how long a token validated by hydra is trusted before it's introspected again,
+  /// a route can override it, and it never extends past the token `exp`
+  pub revalidation_interval_secs: u64,
+  /// how long a token status outlives the token `exp` in redis, so a revoked token
+  /// stays revoked until it can't be used anymore
+  pub status_grace_secs: u64,
+  /// the redis expiry of a token status when the token `exp` is unknown
+  pub status_fallback_ttl_secs: u64,
+  /// the longest lifetime of the access tokens hydra issues, a revoked token with an
+  /// unknown `exp`, and the user not-before epoch are kept this long plus
+  /// `status_grace_secs`, it must be set, and at least the hydra access token lifespan
+  pub max_token_lifetime_secs: u64,
+  /// keep the revoked tokens in memory, synchronized through `revocations_channel`,
+  /// so a revoked token is denied without a redis round-trip
+  pub local_revocations: bool,
+  /// the redis pub/sub channel revocations are published on
+  pub revocations_channel: String,
+  /// the redis pub/sub channel the users service publishes the user events on, every
+  /// event drops the cached auth data of its user, empty disables the subscription
+  pub user_events_channel: String,
+  /// the user events that set the user not-before epoch, E,g a password change,
+  /// which revokes every token issued to the user before the event
+  pub not_before_events: Vec<String>,
+  /// what to do with a token that needs validation while hydra is unreachable,
+  /// a route can override it
+  pub outage_mode: OutageMode,
+  /// how long after its last validation a token is still allowed by `fail_open_stale`
+  pub outage_stale_window_secs: u64,
+  /// how long an instance holds the redis lock of a token it introspects, so concurrent
+  /// requests on other instances wait for its result instead, 0 disables the lock and
+  /// the introspections are only coalesced within an instance
+  pub introspection_lock_ms: u64,
+  /// a token used within this long before its revalidation interval ends is revalidated
+  /// in the background, and the request is served from the cached status, 0 disables it,
+  /// it's clamped to half the interval of the route
+  pub refresh_ahead_secs: u64,
+  /// the maximum number of background revalidations running at once
+  pub refresh_concurrency: usize,
+}
+
+impl Default for TokensConfig {
+  fn default() -> Self {
+    Self {
+      revalidation_interval_secs: 300,
+      status_grace_secs: 3600,
+      status_fallback_ttl_secs: 86400,
+      max_token_lifetime_secs: 86400,
+      local_revocations: true,
+      revocations_channel: "auth:revocations".into(),
+      user_events_channel: "users:events".into(),
+      not_before_events: vec!["password_changed".into(), "password_reset".into()],
+      outage_mode: OutageMode::FailClosed,
+      outage_stale_window_secs: 3600,
+      introspection_lock_ms: 0,
+      refresh_ahead_secs: 60,
+      refresh_concurrency: 16,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Display, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutageMode {
+  /// deny with an internal error
+  #[default]
+  #[display("fail_closed")]
+  FailClosed,
+  /// allow a token that was valid within `TokensConfig::outage_stale_window_secs`
+  #[display("fail_open_stale")]
+  FailOpenStale,
+  /// allow any token that isn't known to be revoked
+  #[display("fail_open")]
+  FailOpen,
+}
+
+/// Where the JWT claims of a request are read from, and the checks on them, done before
+/// the token status is looked up, the claims are only read from a verified token, so the
+/// client can't choose the token id or the subject the status is looked up for
+#[derive(Clone, Debug, Deserialize, Display)]
+#[display(
+  "ClaimsConfig: {sources:?} {metadata_namespace} {metadata_key} {clock_skew_secs} {issuers:?} {audiences:?}"
+)]
+#[serde(default)]
+pub struct ClaimsConfig {
+  /// the claim sources in priority order, the first one holding claims is used
+  pub sources: Vec<ClaimsSource>,
+  /// the `metadata_context` namespace the envoy jwt_authn filter writes to
+  pub metadata_namespace: String,
+  /// the `payload_in_metadata` name of the jwt_authn provider
+  pub metadata_key: String,
+  /// allowed clock skew when checking `exp` and `nbf`
+  pub clock_skew_secs: u64,
+  /// accepted `iss` values, empty accepts any issuer, for the locally verified tokens too
+  pub issuers: Vec<String>,
+  /// accepted `aud` values, one of them must be in the token `aud`, empty skips the check,
+  /// for the locally verified tokens too
+  pub audiences: Vec<String>,
+}
+
+impl Default for ClaimsConfig {
+  fn default() -> Self {
+    Self {
+      sources: vec![ClaimsSource::JwtAuthnMetadata, ClaimsSource::VerifiedToken],
+      metadata_namespace: "envoy.filters.http.jwt_authn".into(),
+      metadata_key: "jwt_payload".into(),
+      clock_skew_secs: 30,
+      issuers: vec![],
+      audiences: vec![],
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Display, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClaimsSource {
+  /// the token payload the envoy jwt_authn filter verified, and put in the `metadata_context`
+  #[display("jwt_authn_metadata")]
+  JwtAuthnMetadata,
+  /// the payload of the bearer JWT, verified locally against the hydra signing keys,
+  /// it needs `JwksConfig::enabled`
+  #[display("verified_token")]
+  VerifiedToken,
+}
+
+/// The user auth data (roles, props, user type) cached in redis, the access decisions are
+/// made on it, so it's kept for a bounded time, and dropped on the user events
+#[derive(Clone, Debug, Deserialize, Display)]
+#[display("UsersConfig: {cache_ttl_secs}")]
+#[serde(default)]
+pub struct UsersConfig {
+  /// how long a cached user is trusted, E,g a demoted user keeps their roles this long
+  /// if the users service doesn't publish an event for it
+  pub cache_ttl_secs: u64,
+}
+
+impl Default for UsersConfig {
+  fn default() -> Self {
+    Self { cache_ttl_secs: 300 }
+  }
+}
+
+/// Binds a token to the device it's first used from, so a token replayed from another
+/// device is detected, the device is identified by a client provided header or cookie
+#[derive(Clone, Debug, Deserialize, Display)]
+#[display("DevicesConfig: {header} {cookie} {mismatch}")]
+#[serde(default)]
+pub struct DevicesConfig {
+  /// the request header holding the device id, checked before the cookie
+  pub header: String,
+  /// the cookie holding the device id
+  pub cookie: String,
+  /// what to do with a token used from another device, a route can override it
+  pub mismatch: DeviceMismatchPolicy,
+}
+
+impl Default for DevicesConfig {
+  fn default() -> Self {
+    Self {
+      header: "x-device-id".into(),
+      cookie: "device_id".into(),
+      mismatch: DeviceMismatchPolicy::Flag,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Display, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceMismatchPolicy {
+  /// allow, only recorded in the metrics and the logs
+  #[display("ignore")]
+  Ignore,
+  /// allow, and flag the request to the upstream service with `x-auth-device-mismatch`
+  #[default]
+  #[display("flag")]
+  Flag,
+  /// deny the request
+  #[display("deny")]
+  Deny,
+}
+
+/// The per-user session registry, a session is a token the user holds, recorded with its
+/// device, first and last use, client ip and user agent
+#[derive(Clone, Debug, Deserialize, Display)]
+#[display(
+  "SessionsConfig: {enabled} {touch_interval_secs} {limits:?} {on_limit} {idle_timeout_secs} {role_idle_timeouts:?}"
+)]
+#[serde(default)]
+pub struct SessionsConfig {
+  pub enabled: bool,
+  /// the minimum time between two records of the last use of a session,
+  /// a change of the client ip or user agent is recorded right away
+  pub touch_interval_secs: u64,
+  /// the concurrent sessions limits, the first limit matching the user applies,
+  /// a user matching none has no limit
+  pub limits: Vec<SessionLimit>,
+  /// what to do with the session exceeding the limit of its user
+  pub on_limit: SessionLimitPolicy,
+  /// the time a session can go unused before its token is denied, 0 disables it, it's
+  /// measured from the last recorded use, so keep it well above `touch_interval_secs`
+  pub idle_timeout_secs: u64,
+  /// role => idle timeout, the strictest timeout of the user roles overrides `idle_timeout_secs`
+  pub role_idle_timeouts: HashMap<String, u64>,
+}
+
+impl Default for SessionsConfig {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      touch_interval_secs: 60,
+      limits: vec![],
+      on_limit: SessionLimitPolicy::EvictOldest,
+      idle_timeout_secs: 0,
+      role_idle_timeouts: HashMap::new(),
+    }
+  }
+}
+
+/// The maximum concurrent sessions of the users with the `user_type` and the `role`,
+/// a limit without both applies to every user
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SessionLimit {
+  #[serde(default)]
+  pub user_type: Option<String>,
+  #[serde(default)]
+  pub role: Option<String>,
+  pub max_sessions: usize,
+}
+
+impl SessionLimit {
+  pub fn matches(&self, user: &UserAuthData) -> bool {
+    let has_role = |role: &String| user.data.roles.split(',').any(|r| r.trim() == role);
+    self.user_type.as_ref().is_none_or(|t| *t == user.user_type)
+      && self.role.as_ref().is_none_or(has_role)
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Display, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionLimitPolicy {
+  /// terminate the least recently started sessions of the user to make room for the new one
+  #[default]
+  #[display("evict_oldest")]
+  EvictOldest,
+  /// terminate the new session, and deny the request
+  #[display("deny_newest")]
+  DenyNewest,
+}
+
+/// Where the route protection table is loaded from, sources are merged in this order:
+/// inline `entries`, then `file`, then the latest version in the database, so a later source
+/// overrides an earlier one for the same route
+#[derive(Clone, Debug, Default, Deserialize, Display)]
+#[display(
+  "RoutesConfig: {version} {file} {database} {reload_interval_secs} {unmatched}",
+  file = file.as_deref().unwrap_or("None")
+)]
+pub struct RoutesConfig {
+  #[serde(default)]
+  pub version: String,
+  /// a YAML or JSON file (decided by the extension) holding a `RouteTableRecord`
+  #[serde(default)]
+  pub file: Option<String>,
+  /// load the latest route table version from the `auth_routes` table
+  #[serde(default)]
+  pub database: bool,
+  /// how often the table is reloaded and swapped at runtime, 0 disables reloading
+  #[serde(default)]
+  pub reload_interval_secs: u64,
+  /// what to do with a request that no entry matches
+  #[serde(default)]
+  pub unmatched: UnmatchedRoutePolicy,
+  #[serde(default)]
+  pub entries: Vec<RouteEntry>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Display, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnmatchedRoutePolicy {
+  /// deny with 403
+  #[display("deny_forbidden")]
+  DenyForbidden,
+  /// deny with 404 and a localized body
+  #[default]
+  #[display("deny_not_found")]
+  DenyNotFound,
+  /// allow only with a valid token, as if the route is protected
+  #[display("protected")]
+  Protected,
+}
+
+/// Local verification of JWT access tokens against the hydra signing keys, opaque tokens,
+/// and JWT tokens that are due for a revocation check, are still introspected, the
+/// accepted issuers and audiences are the `ClaimsConfig` ones
+#[derive(Clone, Debug, Default, Deserialize, Display)]
+#[display(
+  "JwksConfig: {enabled} {url} {file} {leeway_secs} {introspection_interval_secs}",
+  url = url.as_deref().unwrap_or("None"),
+  file = file.as_deref().unwrap_or("None")
+)]
+pub struct JwksConfig {
+  #[serde(default)]
+  pub enabled: bool,
+  /// defaults to `<oauth provider url>/.well-known/jwks.json`
+  #[serde(default)]
+  pub url: Option<String>,
+  /// a local JWKS file, used instead of the url when set
+  #[serde(default)]
+  pub file: Option<String>,
+  /// allowed clock skew when checking `exp` and `nbf`
+  #[serde(default = "default_jwks_leeway_secs")]
+  pub leeway_secs: u64,
+  /// the keys are refetched when they are older than this
+  #[serde(default = "default_jwks_cache_ttl_secs")]
+  pub cache_ttl_secs: u64,
+  /// the minimum time between two refetches, caused by tokens signed with an unknown key
+  #[serde(default = "default_jwks_refresh_interval_secs")]
+  pub refresh_interval_secs: u64,
+  /// how often a locally verified token is still introspected, to catch revocations,
+  /// 0 disables introspection for locally verified tokens
+  #[serde(default = "default_jwks_introspection_interval_secs")]
+  pub introspection_interval_secs: u64,
+}
+
+fn default_jwks_leeway_secs() -> u64 {
+  30
+}
+
+fn default_jwks_cache_ttl_secs() -> u64 {
+  3600
+}
+
+fn default_jwks_refresh_interval_secs() -> u64 {
+  30
+}
+
+fn default_jwks_introspection_interval_secs() -> u64 {
+  300
+}
+
+/// The admin gRPC service, served next to the authorization service
+#[derive(Clone, Debug, Deserialize, Display)]
+#[display("AdminConfig: {enabled} {scopes:?}")]
+#[serde(default)]
+pub struct AdminConfig {
+  pub enabled: bool,
+  /// the scopes a caller token must be granted by hydra, to use the admin service, it
+  /// can't be empty when the service is enabled
+  pub scopes: Vec<String>,
+}
+
+impl Default for AdminConfig {
+  fn default() -> Self {
+    Self { enabled: false, scopes: vec!["auth.admin".into()] }
+  }
+}
@@ -1,2 +1,8 @@
 pub mod config;
 pub mod network;
+pub mod policy;
+pub mod redis;
+pub mod routes;
+pub mod session;
+pub mod token;
+pub mod user;
@@ -0,0 +1,76 @@
+use serde::Deserialize;
+
+/// A declarative authorization rule, evaluated after the token is validated.
+///
+/// A policy applies to a request when one of its `routes` matches, and all of its `when`
+/// conditions hold, an applied policy denies the request unless all of its `require`
+/// conditions hold, E,g:
+///
+/// ```yaml
+/// - name: verified-suppliers-update-products
+///   routes: [/products.v1.ProductsService/Update]
+///   when: [{ attribute: user.roles, op: any_of, values: [supplier] }]
+///   require: [{ attribute: user.props.verified, op: any_of, values: ["true"] }]
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Policy {
+  pub name: String,
+  /// route patterns, with the same syntax as the route table
+  pub routes: Vec<String>,
+  #[serde(default)]
+  pub when: Vec<Condition>,
+  #[serde(default)]
+  pub require: Vec<Condition>,
+  /// logged with the deny decision, defaults to the failed condition
+  #[serde(default)]
+  pub reason: Option<String>,
+  /// a shadow policy is evaluated, and its decision is recorded, but it's never enforced
+  #[serde(default)]
+  pub shadow: bool,
+}
+
+impl Policy {
+  /// Checks if the policy only has conditions over the request, so it applies to the
+  /// requests without a valid token too (E,g on public routes)
+  pub fn is_request_only(&self) -> bool {
+    self.when.iter().chain(&self.require).all(|c| c.attribute.starts_with("request."))
+  }
+}
+
+/// A condition over one attribute of the request, the supported attributes are:
+///
+/// * `request.path`, `request.method`, `request.ip`, `request.header.<name>`
+/// * `request.params.<name>`, the path params of the matched route (E,g `/users/{id}`)
+/// * `claims.iss`, `claims.sub`, `claims.aud`, `claims.jti`, `claims.<custom claim>`
+/// * `token.scopes`
+/// * `user.roles`, `user.props`, `user.props.<key>`, `user.is_oauth`
+///
+/// an attribute resolves to a list of values (E,g the user roles), a missing attribute
+/// resolves to an empty list, an unknown attribute fails loading the policies
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Condition {
+  pub attribute: String,
+  pub op: ConditionOp,
+  #[serde(default)]
+  pub values: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConditionOp {
+  /// any of the attribute values is one of `values`
+  #[default]
+  AnyOf,
+  /// none of the attribute values is one of `values`
+  NoneOf,
+  /// every one of `values` is in the attribute values
+  AllOf,
+  /// any of the attribute values starts with one of `values`
+  Prefix,
+  /// any of the attribute values is an IP inside one of the `values` CIDR ranges
+  InCidr,
+  /// the attribute has at least one value
+  Present,
+  /// the attribute has no values
+  Absent,
+}
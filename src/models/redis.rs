@@ -0,0 +1,49 @@
+/// returns redis key of the cached auth data of a user, keyed by id, unlike the shared
+/// `auth_user_data_key` which is keyed by email
+///
+/// * `user_id`: is the jwt `sub`
+pub fn auth_user_data_by_id_key(user_id: &str) -> String {
+  format!("auth:user_data#{}", user_id)
+}
+
+/// returns redis key of the set of token ids seen for a user
+///
+/// * `user_id`: is the jwt `sub`
+pub fn auth_user_tokens_key(user_id: &str) -> String {
+  format!("auth:user_tokens#{}", user_id)
+}
+
+/// returns redis key of the set of token ids seen for a device
+///
+/// * `device_id`: is the device the token is bound to
+pub fn auth_device_tokens_key(device_id: &str) -> String {
+  format!("auth:device_tokens#{}", device_id)
+}
+
+/// returns redis key of the user not-before epoch, tokens issued before it are revoked
+///
+/// * `user_id`: is the jwt `sub`
+pub fn auth_user_not_before_key(user_id: &str) -> String {
+  format!("auth:user_not_before#{}", user_id)
+}
+
+/// returns redis key of the sorted set of revoked token ids, scored by the time until
+/// they are kept, used to backfill the in-memory revocations of a fresh instance
+pub fn auth_revoked_tokens_key() -> String {
+  "auth:revoked_tokens".to_string()
+}
+
+/// returns redis key of the lock held by the instance introspecting a token
+///
+/// * `jti`: is the jwt id
+pub fn auth_introspection_lock_key(jti: &str) -> String {
+  format!("auth:introspection_lock#{}", jti)
+}
+
+/// returns redis key of the hash recording a session, see `SessionRecord`, the sessions
+/// of a user are the token ids in `auth_user_tokens_key`
+///
+/// * `jti`: is the jwt id
+pub fn auth_session_key(jti: &str) -> String {
+  format!("auth:session#{}", jti)
+}
@@ -0,0 +1,93 @@
+use serde::Deserialize;
+
+use super::config::{DeviceMismatchPolicy, OutageMode};
+
+/// A single entry of the route protection table
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct RouteEntry {
+  /// an exact path, or a pattern, see `utils::matcher::RoutePattern` for the syntax
+  pub path: String,
+  /// the HTTP methods this entry applies to, empty applies to any method,
+  /// an entry with methods wins over one without for the same path
+  #[serde(default)]
+  pub methods: Vec<String>,
+  #[serde(flatten)]
+  pub rule: RouteRule,
+  /// a candidate rule that is evaluated next to the enforced `rule`, its decision is
+  /// only recorded, so a tighter protection can be rolled out safely
+  #[serde(default)]
+  pub shadow: Option<RouteRule>,
+  /// overrides `TokensConfig::revalidation_interval_secs` for this route, E,g a short
+  /// window for checkout routes, it also bounds the revocation checks of locally verified tokens
+  #[serde(default)]
+  pub revalidation_interval_secs: Option<u64>,
+  /// overrides `TokensConfig::outage_mode` for this route, E,g fail open for catalog browsing
+  #[serde(default)]
+  pub outage: Option<OutageMode>,
+  /// overrides `DevicesConfig::mismatch` for this route, E,g deny for payment routes
+  #[serde(default)]
+  pub device_mismatch: Option<DeviceMismatchPolicy>,
+}
+
+/// The protection rule of a route
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct RouteRule {
+  #[serde(default)]
+  pub protected: bool,
+  /// the roles the user must have, only valid on protected routes
+  #[serde(default)]
+  pub roles: RolesRequirement,
+  /// the OAuth scopes the token must be granted, only valid on protected routes
+  #[serde(default)]
+  pub scopes: Vec<String>,
+}
+
+/// Roles required to access a route, checked against the user cached roles
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct RolesRequirement {
+  /// the user must have at least one of these roles
+  #[serde(default)]
+  pub any_of: Vec<String>,
+  /// the user must have every one of these roles
+  #[serde(default)]
+  pub all_of: Vec<String>,
+}
+
+impl RouteEntry {
+  /// Checks if the enforced or the shadow rule requires a valid token
+  pub fn needs_token(&self) -> bool {
+    self.rule.protected || self.shadow.as_ref().is_some_and(|s| s.protected)
+  }
+
+  /// Checks if the enforced or the shadow rule requires scopes
+  pub fn needs_scopes(&self) -> bool {
+    !self.rule.scopes.is_empty() || self.shadow.as_ref().is_some_and(|s| !s.scopes.is_empty())
+  }
+}
+
+impl RouteRule {
+  /// Checks if roles or scopes are set on a rule that isn't protected
+  pub fn is_valid(&self) -> bool {
+    self.protected || (self.roles.is_empty() && self.scopes.is_empty())
+  }
+}
+
+impl RolesRequirement {
+  pub fn is_empty(&self) -> bool {
+    self.any_of.is_empty() && self.all_of.is_empty()
+  }
+
+  pub fn satisfied_by(&self, roles: &[&str]) -> bool {
+    let has = |role: &String| roles.contains(&role.as_str());
+    (self.any_of.is_empty() || self.any_of.iter().any(has)) && self.all_of.iter().all(has)
+  }
+}
+
+/// The shape of a route table, either from a file, or from the `auth_routes` table
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RouteTableRecord {
+  #[serde(default)]
+  pub version: String,
+  #[serde(default)]
+  pub routes: Vec<RouteEntry>,
+}
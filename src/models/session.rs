@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+/// A session (a token the user holds) as recorded in redis under `auth_session_key`,
+/// it's a hash, so the validation and the check path update their own fields
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionRecord {
+  pub jti: String,
+  /// the device the token is bound to, empty if unknown
+  pub device_id: String,
+  /// unix seconds of the first and the latest use of the token
+  pub first_seen: i64,
+  pub last_seen: i64,
+  /// the client ip and user agent of the latest use
+  pub ip: String,
+  pub user_agent: String,
+  /// the token expiry (unix seconds), None if unknown
+  pub exp: Option<i64>,
+}
+
+impl SessionRecord {
+  /// Builds a session from its redis hash fields, None if the hash is empty (the session
+  /// expired or was never recorded)
+  pub fn from_fields(jti: &str, fields: &HashMap<String, String>) -> Option<Self> {
+    if fields.is_empty() {
+      return None;
+    }
+
+    let get = |key: &str| fields.get(key).cloned().unwrap_or_default();
+    let get_i64 = |key: &str| fields.get(key).and_then(|v| v.parse::<i64>().ok());
+    Some(Self {
+      jti: jti.to_string(),
+      device_id: get("device_id"),
+      first_seen: get_i64("first_seen").unwrap_or_default(),
+      last_seen: get_i64("last_seen").unwrap_or_default(),
+      ip: get("ip"),
+      user_agent: get("user_agent"),
+      exp: get_i64("exp"),
+    })
+  }
+
+  /// The latest recorded use of the session, unix seconds, the last use is recorded
+  /// at most once per `SessionsConfig::touch_interval_secs`
+  pub fn last_active(&self) -> i64 {
+    self.last_seen.max(self.first_seen)
+  }
+}
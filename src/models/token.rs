@@ -0,0 +1,39 @@
+use megacommerce_proto::CachedTokenStatus;
+use serde::{Deserialize, Serialize};
+
+/// The token status cached in redis under `auth_token_status_key`, it's a superset of
+/// `CachedTokenStatus`, so other services reading the same key keep working
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct TokenStatus {
+  #[serde(flatten)]
+  pub status: CachedTokenStatus,
+  /// the OAuth scopes granted to the token by the last introspection,
+  /// None if the entry was cached before scopes were recorded
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub scopes: Option<Vec<String>>,
+  /// the token expiry (unix seconds) reported by the last introspection,
+  /// the cached status is never trusted past it
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub exp: Option<i64>,
+  /// the `utils::token::token_hash` of the token last validated, a status recorded by
+  /// another instance is only trusted for the same token, not for any holding its jti
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub token_hash: Option<String>,
+}
+
+/// Published on `TokensConfig::revocations_channel` when a token is revoked
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RevocationEvent {
+  pub jti: String,
+  /// unix seconds until the revocation is kept, same as the redis token status
+  pub until: i64,
+}
+
+/// Published by the users service on `TokensConfig::user_events_channel`
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct UserEvent {
+  /// E,g `password_changed`
+  #[serde(rename = "type")]
+  pub event_type: String,
+  pub user_id: String,
+}
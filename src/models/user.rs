@@ -0,0 +1,13 @@
+use megacommerce_proto::CachedUserData;
+use serde::{Deserialize, Serialize};
+
+/// The user auth data cached in redis under `auth_user_data_by_id_key`, it's a superset of
+/// `CachedUserData`, so other services reading the same key keep working
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct UserAuthData {
+  #[serde(flatten)]
+  pub data: CachedUserData,
+  /// E,g: customer, supplier, empty if the entry was cached before it was recorded
+  #[serde(default)]
+  pub user_type: String,
+}
@@ -0,0 +1,4 @@
+//! The auth service own gRPC APIs, generated by `build.rs` from the definitions under `proto/`
+pub mod auth_admin {
+  tonic::include_proto!("auth.v1");
+}
@@ -94,8 +94,15 @@ impl Server {
     self.store =
       Some(Arc::new(RwLock::new(AuthStoreImpl::new(AuthStoreImplArgs { db: self.db() }))));
 
-    let controller_args =
-      { ControllerArgs { config: self.config(), redis_con: self.redis(), store: self.store() } };
+    let controller_args = {
+      let service_config = self.service_config.lock().await.clone();
+      ControllerArgs {
+        config: self.config(),
+        service_config,
+        redis_con: self.redis(),
+        store: self.store(),
+      }
+    };
 
     let controller = Controller::new(controller_args).await;
     controller.run().await?;
@@ -1,15 +1,31 @@
 use std::{fmt, sync::Arc};
 
-use megacommerce_proto::CachedUserData;
 use megacommerce_shared::{models::context::Context, store::errors::DBError};
 
+use crate::models::{routes::RouteTableRecord, user::UserAuthData};
+
 #[tonic::async_trait]
 pub trait AuthStore: fmt::Debug + Send + Sync {
   /// Gets user information about auth status, E,g if user registered with social account
   /// roles, user type (E,g supplier), ....
+  ///
+  /// * `user_id`: is the jwt `sub`
+  ///
+  /// None if the user doesn't exist, E,g it's deleted while its tokens are still valid
   async fn user_get_auth_data(
+    &self,
+    ctx: Arc<Context>,
+    user_id: &str,
+  ) -> Result<Option<UserAuthData>, DBError>;
+
+  /// Same as `user_get_auth_data`, for a user known by email only
+  async fn user_get_auth_data_by_email(
     &self,
     ctx: Arc<Context>,
     email: &str,
-  ) -> Result<CachedUserData, DBError>;
+  ) -> Result<Option<UserAuthData>, DBError>;
+
+  /// Gets the latest version of the route protection table, None if no version is stored yet
+  async fn routes_get_latest(&self, ctx: Arc<Context>)
+    -> Result<Option<RouteTableRecord>, DBError>;
 }
@@ -1,19 +1,40 @@
 use std::sync::Arc;
 
-use megacommerce_proto::CachedUserData;
 use megacommerce_shared::{models::context::Context, store::errors::DBError};
 
-use crate::store::database::AuthStore;
+use crate::{
+  models::{routes::RouteTableRecord, user::UserAuthData},
+  store::database::AuthStore,
+};
 
-use super::{user::user_get_auth_data, AuthStoreImpl};
+use super::{
+  routes::routes_get_latest,
+  user::{user_get_auth_data, user_get_auth_data_by_email},
+  AuthStoreImpl,
+};
 
 #[tonic::async_trait]
 impl AuthStore for AuthStoreImpl {
   async fn user_get_auth_data(
+    &self,
+    ctx: Arc<Context>,
+    user_id: &str,
+  ) -> Result<Option<UserAuthData>, DBError> {
+    user_get_auth_data(self, ctx, user_id).await
+  }
+
+  async fn user_get_auth_data_by_email(
     &self,
     ctx: Arc<Context>,
     email: &str,
-  ) -> Result<CachedUserData, DBError> {
-    user_get_auth_data(self, ctx, email).await
+  ) -> Result<Option<UserAuthData>, DBError> {
+    user_get_auth_data_by_email(self, ctx, email).await
+  }
+
+  async fn routes_get_latest(
+    &self,
+    ctx: Arc<Context>,
+  ) -> Result<Option<RouteTableRecord>, DBError> {
+    routes_get_latest(self, ctx).await
   }
 }
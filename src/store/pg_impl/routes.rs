@@ -0,0 +1,33 @@
+use std::sync::Arc;
+
+use megacommerce_shared::{
+  models::{context::Context, errors::ErrorType},
+  store::errors::{handle_db_error, DBError},
+};
+use sqlx::query;
+
+use crate::models::routes::{RouteEntry, RouteTableRecord};
+
+use super::AuthStoreImpl;
+
+pub async fn routes_get_latest(
+  s: &AuthStoreImpl,
+  _ctx: Arc<Context>,
+) -> Result<Option<RouteTableRecord>, DBError> {
+  let path = "auth.store.routes_get_latest";
+  let row = query!(r#"SELECT version, routes FROM auth_routes ORDER BY created_at DESC LIMIT 1"#)
+    .fetch_optional(&s.db.get().await.clone())
+    .await
+    .map_err(|err| handle_db_error(err, path))?;
+
+  match row {
+    Some(row) => {
+      let routes: Vec<RouteEntry> = serde_json::from_value(row.routes).map_err(|err| {
+        let msg = "failed to deserialize the stored route table";
+        DBError::new(ErrorType::JsonUnmarshal, Box::new(err), msg, path, row.version.clone())
+      })?;
+      Ok(Some(RouteTableRecord { version: row.version, routes }))
+    }
+    None => Ok(None),
+  }
+}
@@ -7,22 +7,48 @@ use megacommerce_shared::{
 };
 use sqlx::query;
 
+use crate::models::user::UserAuthData;
+
 use super::AuthStoreImpl;
 
 pub async fn user_get_auth_data(
+  s: &AuthStoreImpl,
+  _ctx: Arc<Context>,
+  user_id: &str,
+) -> Result<Option<UserAuthData>, DBError> {
+  let row =
+    query!(r#"SELECT user_type, roles, props, auth_service FROM users WHERE id = $1"#, user_id)
+      .fetch_optional(&s.db.get().await.clone())
+      .await
+      .map_err(|err| handle_db_error(err, "auth.store.user_get_auth_data"))?;
+
+  Ok(row.map(|row| UserAuthData {
+    data: CachedUserData {
+      is_oauth: !row.auth_service.unwrap_or_default().is_empty(),
+      roles: row.roles.join(","),
+      props: row.props.unwrap_or_default().join(","),
+    },
+    user_type: row.user_type,
+  }))
+}
+
+pub async fn user_get_auth_data_by_email(
   s: &AuthStoreImpl,
   _ctx: Arc<Context>,
   email: &str,
-) -> Result<CachedUserData, DBError> {
+) -> Result<Option<UserAuthData>, DBError> {
   let row =
     query!(r#"SELECT user_type, roles, props, auth_service FROM users WHERE email = $1"#, email)
-      .fetch_one(&s.db.get().await.clone())
+      .fetch_optional(&s.db.get().await.clone())
       .await
-      .map_err(|err| handle_db_error(err, "auth.store.user_get_auth_data"))?;
+      .map_err(|err| handle_db_error(err, "auth.store.user_get_auth_data_by_email"))?;
 
-  Ok(CachedUserData {
-    is_oauth: !row.auth_service.unwrap_or_default().is_empty(),
-    roles: row.roles.join(","),
-    props: row.props.unwrap_or_default().join(","),
-  })
+  Ok(row.map(|row| UserAuthData {
+    data: CachedUserData {
+      is_oauth: !row.auth_service.unwrap_or_default().is_empty(),
+      roles: row.roles.join(","),
+      props: row.props.unwrap_or_default().join(","),
+    },
+    user_type: row.user_type,
+  }))
 }
@@ -0,0 +1,149 @@
+use megacommerce_proto::{
+  google::protobuf::{value::Kind as PbKind, Value as PbValue},
+  service::auth::v3::CheckRequest,
+  value::Kind,
+  JwtClaims, ListValue, Struct, Timestamp, Value,
+};
+use serde_json::{Map, Value as JsonValue};
+
+use crate::models::config::ClaimsConfig;
+
+/// The claims with a `JwtClaims` field, the others go to `JwtClaims::custom`
+const REGISTERED_CLAIMS: [&str; 7] = ["iss", "sub", "aud", "exp", "nbf", "iat", "jti"];
+
+/// Reads the token payload the envoy jwt_authn filter puts in the `metadata_context`
+pub fn claims_from_jwt_authn_metadata(
+  req: &CheckRequest,
+  config: &ClaimsConfig,
+) -> Option<JwtClaims> {
+  let payload = req
+    .attributes
+    .as_ref()?
+    .metadata_context
+    .as_ref()?
+    .filter_metadata
+    .get(&config.metadata_namespace)?
+    .fields
+    .get(&config.metadata_key)?;
+  let Some(PbKind::StructValue(payload)) = &payload.kind else {
+    return None;
+  };
+
+  let kind = |key: &str| payload.fields.get(key).and_then(|v| v.kind.as_ref());
+  let string = |key: &str| match kind(key) {
+    Some(PbKind::StringValue(s)) => s.clone(),
+    _ => String::new(),
+  };
+  let timestamp = |key: &str| {
+    let seconds = match kind(key) {
+      Some(PbKind::NumberValue(n)) => *n as i64,
+      Some(PbKind::StringValue(s)) => s.parse::<i64>().ok()?,
+      _ => return None,
+    };
+    Some(Timestamp { seconds, nanos: 0 })
+  };
+
+  // E,g: "aud": "api" or "aud": ["api", "web"]
+  let aud = match kind("aud") {
+    Some(PbKind::StringValue(s)) => vec![s.clone()],
+    Some(PbKind::ListValue(list)) => list
+      .values
+      .iter()
+      .filter_map(|v| match &v.kind {
+        Some(PbKind::StringValue(s)) => Some(s.clone()),
+        _ => None,
+      })
+      .collect(),
+    _ => vec![],
+  };
+
+  let custom = payload
+    .fields
+    .iter()
+    .filter(|(key, _)| !REGISTERED_CLAIMS.contains(&key.as_str()))
+    .map(|(key, value)| (key.clone(), to_claim_value(value)))
+    .collect();
+
+  Some(JwtClaims {
+    iss: string("iss"),
+    sub: string("sub"),
+    aud,
+    exp: timestamp("exp"),
+    nbf: timestamp("nbf"),
+    iat: timestamp("iat"),
+    jti: string("jti"),
+    custom,
+  })
+}
+
+/// Reads the claims of a verified JWT payload
+pub fn claims_from_token_payload(payload: &Map<String, JsonValue>) -> JwtClaims {
+  let string = |key: &str| payload.get(key).and_then(JsonValue::as_str).unwrap_or_default();
+  let timestamp = |key: &str| {
+    let seconds = payload.get(key)?.as_i64()?;
+    Some(Timestamp { seconds, nanos: 0 })
+  };
+
+  // E,g: "aud": "api" or "aud": ["api", "web"]
+  let aud = match payload.get("aud") {
+    Some(JsonValue::String(s)) => vec![s.clone()],
+    Some(JsonValue::Array(list)) => {
+      list.iter().filter_map(|v| v.as_str()).map(String::from).collect()
+    }
+    _ => vec![],
+  };
+
+  let custom = payload
+    .iter()
+    .filter(|(key, _)| !REGISTERED_CLAIMS.contains(&key.as_str()))
+    .map(|(key, value)| (key.clone(), json_to_claim_value(value)))
+    .collect();
+
+  JwtClaims {
+    iss: string("iss").into(),
+    sub: string("sub").into(),
+    aud,
+    exp: timestamp("exp"),
+    nbf: timestamp("nbf"),
+    iat: timestamp("iat"),
+    jti: string("jti").into(),
+    custom,
+  }
+}
+
+/// Converts an envoy metadata value to a claim value, they are the same protobuf type,
+/// generated in two packages
+fn to_claim_value(value: &PbValue) -> Value {
+  let kind = value.kind.as_ref().map(|kind| match kind {
+    PbKind::NullValue(n) => Kind::NullValue(*n),
+    PbKind::NumberValue(n) => Kind::NumberValue(*n),
+    PbKind::StringValue(s) => Kind::StringValue(s.clone()),
+    PbKind::BoolValue(b) => Kind::BoolValue(*b),
+    PbKind::StructValue(s) => Kind::StructValue(Struct {
+      fields: s.fields.iter().map(|(k, v)| (k.clone(), to_claim_value(v))).collect(),
+    }),
+    PbKind::ListValue(list) => {
+      Kind::ListValue(ListValue { values: list.values.iter().map(to_claim_value).collect() })
+    }
+  });
+
+  Value { kind }
+}
+
+/// Converts a JSON value of a token payload to a claim value
+fn json_to_claim_value(value: &JsonValue) -> Value {
+  let kind = match value {
+    JsonValue::Null => Kind::NullValue(0),
+    JsonValue::Bool(b) => Kind::BoolValue(*b),
+    JsonValue::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or_default()),
+    JsonValue::String(s) => Kind::StringValue(s.clone()),
+    JsonValue::Array(list) => {
+      Kind::ListValue(ListValue { values: list.iter().map(json_to_claim_value).collect() })
+    }
+    JsonValue::Object(map) => Kind::StructValue(Struct {
+      fields: map.iter().map(|(k, v)| (k.clone(), json_to_claim_value(v))).collect(),
+    }),
+  };
+
+  Value { kind: Some(kind) }
+}
@@ -0,0 +1,300 @@
+use std::{
+  collections::HashMap,
+  io::{Error, ErrorKind},
+};
+
+/// A compiled route pattern, the kind is inferred from the pattern syntax:
+///
+/// * exact: `/users.v1.UsersService/Login`
+/// * prefix: ends with `/**`, E,g `/api/v1/admin/**` matches anything under `/api/v1/admin`
+/// * template: contains `{name}` segments, E,g `/api/v1/products/{id}`
+/// * glob: contains `*` inside a segment, E,g `/users.v1.UsersService/*` or `/api/v1/Get*`
+///
+/// template and glob segments can be mixed, and they both match exactly one path segment
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoutePattern {
+  Exact(String),
+  Segments(Vec<Segment>),
+  Prefix(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+  Literal(String),
+  Param(String),
+  Glob(String),
+}
+
+impl Segment {
+  // a literal segment is more specific than a glob with some literal text,
+  // which is more specific than a param or a bare `*`
+  fn weight(&self) -> usize {
+    match self {
+      Segment::Literal(_) => 3,
+      Segment::Glob(g) if g != "*" => 2,
+      Segment::Glob(_) | Segment::Param(_) => 1,
+    }
+  }
+}
+
+/// The precedence of a pattern, the greater value is the more specific one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Specificity {
+  rank: u8,
+  weight: usize,
+}
+
+impl RoutePattern {
+  pub fn parse(pattern: &str) -> Result<Self, Error> {
+    let invalid = |msg: &str| {
+      Error::new(ErrorKind::InvalidInput, format!("invalid route pattern {}: {}", pattern, msg))
+    };
+
+    if !pattern.starts_with('/') {
+      return Err(invalid("must start with /"));
+    }
+
+    if let Some(prefix) = pattern.strip_suffix("/**") {
+      if prefix.contains('*') || prefix.contains('{') {
+        return Err(invalid("a prefix pattern can't contain globs or params"));
+      }
+      return Ok(RoutePattern::Prefix(prefix.to_string()));
+    }
+
+    if !pattern.contains('*') && !pattern.contains('{') {
+      return Ok(RoutePattern::Exact(pattern.to_string()));
+    }
+
+    let mut segments = vec![];
+    for seg in pattern[1..].split('/') {
+      if seg.contains("**") {
+        return Err(invalid("** is only allowed as the last segment"));
+      }
+
+      if let Some(name) = seg.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+        if name.is_empty() || name.contains(['{', '}', '*']) {
+          return Err(invalid("malformed path param"));
+        }
+        segments.push(Segment::Param(name.to_string()));
+      } else if seg.contains(['{', '}']) {
+        return Err(invalid("a path param must span the whole segment"));
+      } else if seg.contains('*') {
+        segments.push(Segment::Glob(seg.to_string()));
+      } else {
+        segments.push(Segment::Literal(seg.to_string()));
+      }
+    }
+
+    Ok(RoutePattern::Segments(segments))
+  }
+
+  /// exact patterns win over segment patterns, which win over prefixes
+  pub fn specificity(&self) -> Specificity {
+    match self {
+      RoutePattern::Exact(p) => Specificity { rank: 2, weight: p.len() },
+      RoutePattern::Segments(s) => {
+        Specificity { rank: 1, weight: s.iter().map(|seg| seg.weight()).sum() }
+      }
+      RoutePattern::Prefix(p) => Specificity { rank: 0, weight: p.len() },
+    }
+  }
+
+  /// Matches the path (without the query string), returning the captured path params
+  pub fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+    match self {
+      RoutePattern::Exact(p) => (p == path).then(HashMap::new),
+      RoutePattern::Prefix(p) => {
+        let rest = path.strip_prefix(p.as_str())?;
+        (rest.is_empty() || rest.starts_with('/')).then(HashMap::new)
+      }
+      RoutePattern::Segments(segments) => {
+        let parts: Vec<&str> = path.strip_prefix('/')?.split('/').collect();
+        if parts.len() != segments.len() {
+          return None;
+        }
+
+        let mut params = HashMap::new();
+        for (seg, part) in segments.iter().zip(parts) {
+          match seg {
+            Segment::Literal(l) if l == part => {}
+            Segment::Param(name) if !part.is_empty() => {
+              params.insert(name.clone(), part.to_string());
+            }
+            Segment::Glob(g) if glob_match(g, part) => {}
+            _ => return None,
+          }
+        }
+        Some(params)
+      }
+    }
+  }
+}
+
+/// Matches a single segment against a glob where `*` matches any run of characters
+pub fn glob_match(glob: &str, value: &str) -> bool {
+  let parts: Vec<&str> = glob.split('*').collect();
+  let (first, last) = (parts[0], parts[parts.len() - 1]);
+
+  if parts.len() == 1 {
+    return glob == value;
+  }
+  if value.len() < first.len() + last.len() || !value.starts_with(first) || !value.ends_with(last) {
+    return false;
+  }
+
+  let mut rest = &value[first.len()..value.len() - last.len()];
+  for part in &parts[1..parts.len() - 1] {
+    match rest.find(part) {
+      Some(idx) => rest = &rest[idx + part.len()..],
+      None => return false,
+    }
+  }
+  true
+}
+
+/// The segments of a path kept by `normalize_path`, the rest are folded into `/**`
+const NORMALIZED_SEGMENTS: usize = 4;
+
+/// Normalizes a request path to a route pattern with a bounded number of values, to be used
+/// as a metric label: the query is dropped, the id-like segments (numbers, uuids, ulids, ...)
+/// become `{id}`, and the segments past the fourth become `/**`,
+/// E,g `/api/v1/products/42/reviews/7?page=2` => `/api/v1/products/{id}/**`
+pub fn normalize_path(path: &str) -> String {
+  let path = path.split('?').next().unwrap_or_default();
+  let segments = path.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>();
+
+  let is_id = |s: &str| {
+    s.bytes().all(|b| b.is_ascii_digit())
+      || (s.len() >= 16
+        && s.bytes().any(|b| b.is_ascii_digit())
+        && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'))
+  };
+  let mut normalized = segments
+    .iter()
+    .take(NORMALIZED_SEGMENTS)
+    .map(|s| if is_id(s) { "{id}" } else { s })
+    .fold(String::new(), |acc, s| acc + "/" + s);
+
+  if segments.len() > NORMALIZED_SEGMENTS {
+    normalized.push_str("/**");
+  }
+  if normalized.is_empty() {
+    normalized.push('/');
+  }
+  normalized
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn matches(pattern: &str, path: &str) -> Option<HashMap<String, String>> {
+    RoutePattern::parse(pattern).unwrap().matches(path)
+  }
+
+  #[test]
+  fn parses_the_pattern_kinds() {
+    let cases = [
+      ("/users.v1.UsersService/Login", "exact"),
+      ("/api/v1/admin/**", "prefix"),
+      ("/api/v1/products/{id}", "segments"),
+      ("/users.v1.UsersService/*", "segments"),
+    ];
+    for (pattern, kind) in cases {
+      let parsed = match RoutePattern::parse(pattern).unwrap() {
+        RoutePattern::Exact(_) => "exact",
+        RoutePattern::Prefix(_) => "prefix",
+        RoutePattern::Segments(_) => "segments",
+      };
+      assert_eq!(parsed, kind, "{}", pattern);
+    }
+  }
+
+  #[test]
+  fn rejects_malformed_patterns() {
+    for pattern in ["api/v1", "/api/**/x", "/api/*/**", "/api/{}", "/api/x{id}", "/api/{a*}"] {
+      assert!(RoutePattern::parse(pattern).is_err(), "{}", pattern);
+    }
+  }
+
+  #[test]
+  fn matches_the_paths() {
+    let cases = [
+      // exact
+      ("/api/v1/products", "/api/v1/products", true),
+      ("/api/v1/products", "/api/v1/products/", false),
+      ("/api/v1/products", "/api/v1/product", false),
+      // params
+      ("/api/v1/products/{id}", "/api/v1/products/42", true),
+      ("/api/v1/products/{id}", "/api/v1/products/", false),
+      ("/api/v1/products/{id}", "/api/v1/products/42/", false),
+      ("/api/v1/products/{id}", "/api/v1/products/42/reviews", false),
+      // single segment globs
+      ("/users.v1.UsersService/*", "/users.v1.UsersService/Login", true),
+      ("/users.v1.UsersService/*", "/users.v1.UsersService/a/b", false),
+      ("/api/v1/Get*", "/api/v1/GetUser", true),
+      ("/api/v1/Get*", "/api/v1/ListUsers", false),
+      // multi `*` globs
+      ("/api/*Service/Get*By*", "/api/UsersService/GetUserById", true),
+      ("/api/*Service/Get*By*", "/api/UsersService/GetUser", false),
+      ("/api/a*b*c", "/api/abc", true),
+      ("/api/a*b*c", "/api/acb", false),
+      ("/api/a*a", "/api/a", false),
+      // prefixes
+      ("/api/v1/admin/**", "/api/v1/admin", true),
+      ("/api/v1/admin/**", "/api/v1/admin/", true),
+      ("/api/v1/admin/**", "/api/v1/admin/users/42", true),
+      ("/api/v1/admin/**", "/api/v1/administrators", false),
+    ];
+    for (pattern, path, expected) in cases {
+      assert_eq!(matches(pattern, path).is_some(), expected, "{} {}", pattern, path);
+    }
+  }
+
+  #[test]
+  fn captures_the_path_params() {
+    let params = matches("/api/v1/stores/{store}/products/{id}", "/api/v1/stores/s1/products/42");
+    let params = params.unwrap();
+    assert_eq!(params.get("store").map(String::as_str), Some("s1"));
+    assert_eq!(params.get("id").map(String::as_str), Some("42"));
+  }
+
+  #[test]
+  fn the_most_specific_pattern_ranks_first() {
+    // each pattern is more specific than the next one
+    let ordered = [
+      "/api/v1/products/42",
+      "/api/v1/products/{id}/reviews",
+      "/api/v1/products/x*",
+      "/api/v1/products/{id}",
+      "/api/v1/products/**",
+      "/api/v1/**",
+    ];
+    for pair in ordered.windows(2) {
+      let a = RoutePattern::parse(pair[0]).unwrap().specificity();
+      let b = RoutePattern::parse(pair[1]).unwrap().specificity();
+      assert!(a > b, "{} should win over {}", pair[0], pair[1]);
+    }
+  }
+
+  #[test]
+  fn a_param_and_a_bare_glob_are_as_specific() {
+    let param = RoutePattern::parse("/api/v1/products/{id}").unwrap().specificity();
+    let glob = RoutePattern::parse("/api/v1/products/*").unwrap().specificity();
+    assert_eq!(param, glob);
+  }
+
+  #[test]
+  fn normalizes_the_paths() {
+    let cases = [
+      ("/", "/"),
+      ("/api/v1/products?page=2", "/api/v1/products"),
+      ("/api/v1/products/42", "/api/v1/products/{id}"),
+      ("/api/v1/products/42/reviews/7", "/api/v1/products/{id}/**"),
+      ("/api/v1/orders/01J9ZQ3X5Y2B7C8D9E0F1G2H3J", "/api/v1/orders/{id}"),
+    ];
+    for (path, normalized) in cases {
+      assert_eq!(normalize_path(path), normalized, "{}", path);
+    }
+  }
+}
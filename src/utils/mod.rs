@@ -1 +1,4 @@
+pub mod claims;
+pub mod matcher;
 pub mod net;
+pub mod token;
@@ -1,10 +1,11 @@
 use std::{
   collections::HashMap,
   io::{Error, ErrorKind},
+  net::IpAddr,
 };
 
 use http::Uri;
-use megacommerce_proto::{service::auth::v3::CheckRequest, JwtClaims, Timestamp};
+use megacommerce_proto::{config::core::v3::address::Address, service::auth::v3::CheckRequest};
 use tonic::Request;
 
 use crate::models::network::EssentialHttpHeaders;
@@ -13,39 +14,103 @@ pub fn validate_url_target(url: &str) -> Result<Uri, Error> {
   url.parse::<Uri>().map_err(|e| Error::new(ErrorKind::InvalidInput, format!("invalid URL: {}", e)))
 }
 
+/// Checks if the ip is inside the CIDR range (E,g 10.0.0.0/8), a range without
+/// a prefix length is treated as a single address
+pub fn ip_in_cidr(ip: &str, cidr: &str) -> bool {
+  let (net, len) = cidr.split_once('/').unwrap_or((cidr, ""));
+  let (Ok(ip), Ok(net)) = (ip.trim().parse::<IpAddr>(), net.parse::<IpAddr>()) else {
+    return false;
+  };
+
+  match (ip, net) {
+    (IpAddr::V4(ip), IpAddr::V4(net)) => {
+      let len = if len.is_empty() { Ok(32) } else { len.parse::<u32>() };
+      match len {
+        Ok(len) if len <= 32 => {
+          let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+          u32::from(ip) & mask == u32::from(net) & mask
+        }
+        _ => false,
+      }
+    }
+    (IpAddr::V6(ip), IpAddr::V6(net)) => {
+      let len = if len.is_empty() { Ok(128) } else { len.parse::<u32>() };
+      match len {
+        Ok(len) if len <= 128 => {
+          let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+          u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+      }
+    }
+    _ => false,
+  }
+}
+
+/// Resolves the client ip of the check request, the entries of the x-forwarded-for list are
+/// appended by every proxy on the way, the left ones are set by the client and never trusted,
+/// the ip is the `trusted_hops`th entry from the right, or the downstream peer address of
+/// envoy when `trusted_hops` is 0 or the list is shorter
+pub fn client_ip(req: &CheckRequest, x_forwarded_for: &str, trusted_hops: usize) -> String {
+  let peer = req
+    .attributes
+    .as_ref()
+    .and_then(|a| a.source.as_ref())
+    .and_then(|s| s.address.as_ref())
+    .and_then(|a| match &a.address {
+      Some(Address::SocketAddress(s)) => Some(s.address.clone()),
+      _ => None,
+    })
+    .unwrap_or_default();
+  if trusted_hops == 0 {
+    return peer;
+  }
+
+  let hops =
+    x_forwarded_for.split(',').map(str::trim).filter(|h| !h.is_empty()).collect::<Vec<_>>();
+  match hops.len().checked_sub(trusted_hops) {
+    Some(i) => hops[i].to_string(),
+    None => peer,
+  }
+}
+
 pub fn extract_jwt_token_from_request<T>(req: &Request<T>) -> Option<String> {
   req.metadata().get("authorization")?.to_str().ok()?.strip_prefix("Bearer ")?.to_string().into()
 }
 
-pub fn extract_jwt_claims_from_request<T>(req: &Request<T>) -> JwtClaims {
-  let meta = req.metadata();
+/// Returns the bearer token of the original http request, in the check request attributes,
+/// falling back to the metadata of the ext_authz call itself
+pub fn extract_jwt_token_from_check_request(req: &Request<CheckRequest>) -> Option<String> {
+  let token = req
+    .get_ref()
+    .attributes
+    .as_ref()
+    .and_then(|a| a.request.as_ref())
+    .and_then(|r| r.http.as_ref())
+    .and_then(|h| h.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("authorization")))
+    .and_then(|(_, v)| v.strip_prefix("Bearer "))
+    .map(String::from);
 
-  let get_header = |key: &str| -> String {
-    meta.get(key).and_then(|v| v.to_str().ok()).unwrap_or_default().to_string()
-  };
+  token.or_else(|| extract_jwt_token_from_request(req))
+}
+
+/// Returns the client device id, from the header of the original http request,
+/// or else from the cookie
+pub fn extract_device_id(req: &CheckRequest, header: &str, cookie: &str) -> Option<String> {
+  let headers = &req.attributes.as_ref()?.request.as_ref()?.http.as_ref()?.headers;
+  let get = |key: &str| headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v);
 
-  // Helper to extract numeric timestamp (exp, nbf, iat)
-  let get_timestamp = |key: &str| -> Option<Timestamp> {
-    meta
-      .get(key)
-      .and_then(|v| v.to_str().ok())
-      .and_then(|s| s.parse::<i64>().ok().map(|secs| Timestamp { seconds: secs, nanos: 0 }))
+  let from_header = get(header).map(|v| v.trim().to_string());
+  // E,g: cookie: theme=dark; device_id=01J...
+  let from_cookie = || {
+    get("cookie")?
+      .split(';')
+      .filter_map(|c| c.trim().split_once('='))
+      .find(|(name, _)| *name == cookie)
+      .map(|(_, value)| value.trim().to_string())
   };
 
-  JwtClaims {
-    iss: get_header("x-jwt-iss"),
-    sub: get_header("x-jwt-sub"),
-    aud: meta
-      .get("x-jwt-aud")
-      .and_then(|v| v.to_str().ok())
-      .map(|s| vec![s.to_string()])
-      .unwrap_or_default(),
-    exp: get_timestamp("x-jwt-exp"),
-    nbf: get_timestamp("x-jwt-nbf"),
-    iat: get_timestamp("x-jwt-iat"),
-    jti: get_header("x-jwt-jti"),
-    custom: Default::default(),
-  }
+  from_header.filter(|v| !v.is_empty()).or_else(from_cookie).filter(|v| !v.is_empty())
 }
 
 pub fn get_essential_http_headers(
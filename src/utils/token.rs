@@ -0,0 +1,7 @@
+use sha2::{Digest, Sha256};
+
+/// The hex sha256 of a token, it identifies the token itself, where its jti can be claimed
+/// by any token forwarded with the same claims
+pub fn token_hash(token: &str) -> String {
+  Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}